@@ -0,0 +1,47 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use rcc::{CompareMode, CompareSettings, avoided_comparisons, find_duplicates, fixtures};
+use std::hint::black_box;
+
+const FIXTURE_SIZES: [usize; 3] = [100, 1_000, 5_000];
+const DUPLICATE_PERCENT: u64 = 30;
+const SEED: u64 = 0x5eed;
+
+fn bench_find_duplicates(c: &mut Criterion) {
+    let mut group = c.benchmark_group("find_duplicates");
+    group.sample_size(10);
+    for size in FIXTURE_SIZES {
+        let fixture = fixtures::generate(size, DUPLICATE_PERCENT, SEED);
+        for mode in [
+            CompareMode::Loose,
+            CompareMode::Paranoid,
+            CompareMode::Exact,
+        ] {
+            let settings = CompareSettings::new(mode);
+            // Loose mode compares every pair, the strict modes only pairs within a name cluster
+            let all_pairs = (fixture.src.len() * fixture.dest.len()) as u64;
+            let compared_pairs =
+                all_pairs - avoided_comparisons(&fixture.src, &fixture.dest, &settings);
+            group.throughput(Throughput::Elements(compared_pairs));
+            group.bench_with_input(
+                BenchmarkId::new(mode.to_string(), size),
+                &fixture,
+                |b, fixture| {
+                    b.iter(|| {
+                        let mut near_misses = vec![];
+                        find_duplicates(
+                            black_box(&fixture.src),
+                            black_box(&fixture.dest),
+                            &settings,
+                            &mut near_misses,
+                        )
+                        .count()
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_find_duplicates);
+criterion_main!(benches);
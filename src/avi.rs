@@ -0,0 +1,161 @@
+use anyhow::{Result, anyhow, bail};
+use chrono::{NaiveDate, NaiveDateTime};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::time::Duration;
+
+const RIFF_HEADER_SIZE: u64 = 12;
+const CHUNK_HEADER_SIZE: u64 = 8;
+// Only the header lists are needed, they come before the `movi` data
+const MAX_HEADER_CHUNKS: usize = 64;
+
+/// Values read from the RIFF headers of an AVI file.
+#[derive(Default)]
+pub struct AviInfo {
+    pub duration: Option<Duration>,
+    pub date: Option<NaiveDateTime>,
+}
+
+pub fn read_avi_info(path: &Path) -> Result<AviInfo> {
+    let file = File::open(path)?;
+    let file_size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut riff = [0u8; RIFF_HEADER_SIZE as usize];
+    reader.read_exact(&mut riff)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"AVI " {
+        bail!("not an AVI file");
+    }
+
+    let mut info = AviInfo::default();
+    let mut offset = RIFF_HEADER_SIZE;
+    for _ in 0..MAX_HEADER_CHUNKS {
+        if offset + CHUNK_HEADER_SIZE > file_size {
+            break;
+        }
+        reader.seek(SeekFrom::Start(offset))?;
+        let (id, size) = read_chunk_header(&mut reader)?;
+        if &id == b"LIST" {
+            // Sizes come from the file, a corrupt one must not reach past its end
+            if size > file_size - offset - CHUNK_HEADER_SIZE {
+                bail!("LIST chunk at {} runs past the end of the file", offset);
+            }
+            let list_size = size.checked_sub(4).ok_or(anyhow!(
+                "LIST chunk at {} is too small for its type",
+                offset
+            ))?;
+            let mut list_type = [0u8; 4];
+            reader.read_exact(&mut list_type)?;
+            match &list_type {
+                b"hdrl" | b"INFO" => read_list(&mut reader, list_size, &mut info)?,
+                b"movi" => break,
+                _ => {}
+            }
+        }
+        // Chunks are padded to an even size
+        offset += CHUNK_HEADER_SIZE + size + size % 2;
+    }
+
+    Ok(info)
+}
+
+fn read_chunk_header(reader: &mut impl Read) -> Result<([u8; 4], u64)> {
+    let mut header = [0u8; CHUNK_HEADER_SIZE as usize];
+    reader.read_exact(&mut header)?;
+    let id = [header[0], header[1], header[2], header[3]];
+    let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    Ok((id, size as u64))
+}
+
+fn read_list(reader: &mut (impl Read + Seek), list_size: u64, info: &mut AviInfo) -> Result<()> {
+    let mut remaining = list_size;
+    while remaining >= CHUNK_HEADER_SIZE {
+        let (id, size) = read_chunk_header(reader)?;
+        // The list was checked against the file length, so this also bounds the allocations
+        if size > remaining - CHUNK_HEADER_SIZE {
+            bail!(
+                "{} chunk runs past the end of its list",
+                String::from_utf8_lossy(&id)
+            );
+        }
+        let padded_size = size + size % 2;
+        match &id {
+            b"avih" if size >= 20 => {
+                let mut avih = vec![0u8; size as usize];
+                reader.read_exact(&mut avih)?;
+                let micro_sec_per_frame = u32::from_le_bytes([avih[0], avih[1], avih[2], avih[3]]);
+                let total_frames = u32::from_le_bytes([avih[16], avih[17], avih[18], avih[19]]);
+                info.duration = Some(Duration::from_micros(
+                    micro_sec_per_frame as u64 * total_frames as u64,
+                ));
+                reader.seek(SeekFrom::Current((padded_size - size) as i64))?;
+            }
+            // IDIT is written by most cameras, ICRD is the generic creation date
+            b"IDIT" | b"ICRD" if info.date.is_none() => {
+                let mut value = vec![0u8; size as usize];
+                reader.read_exact(&mut value)?;
+                info.date = parse_date(&String::from_utf8_lossy(&value));
+                reader.seek(SeekFrom::Current((padded_size - size) as i64))?;
+            }
+            b"LIST" => {
+                // Nested lists like `strl` don't hold anything of interest
+                reader.seek(SeekFrom::Current(padded_size as i64))?;
+            }
+            _ => {
+                reader.seek(SeekFrom::Current(padded_size as i64))?;
+            }
+        }
+        remaining = remaining.saturating_sub(CHUNK_HEADER_SIZE + padded_size);
+    }
+    Ok(())
+}
+
+fn parse_date(value: &str) -> Option<NaiveDateTime> {
+    let value = value.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    // e.g. `SAT JAN 01 12:00:00 2005`
+    if let Ok(date) = NaiveDateTime::parse_from_str(value, "%a %b %d %H:%M:%S %Y") {
+        return Some(date);
+    }
+    if let Ok(date) = NaiveDateTime::parse_from_str(value, "%Y:%m:%d %H:%M:%S") {
+        return Some(date);
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn chunk(id: &[u8; 4], size: u32) -> Vec<u8> {
+        [id.as_slice(), &size.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn list_smaller_than_its_type_is_an_error() {
+        let riff = [
+            chunk(b"RIFF", 16),
+            b"AVI ".to_vec(),
+            chunk(b"LIST", 2),
+            vec![0; 4],
+        ]
+        .concat();
+        let path = std::env::temp_dir().join(format!("rcc-avi-test-{}.avi", std::process::id()));
+        std::fs::write(&path, riff).unwrap();
+        let result = read_avi_info(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn oversized_chunk_in_list_is_an_error() {
+        // A 16 byte list claiming a 4 GB `avih`
+        let list = [chunk(b"avih", u32::MAX), vec![0; 8]].concat();
+        let mut info = AviInfo::default();
+        let result = read_list(&mut Cursor::new(list), 16, &mut info);
+        assert!(result.is_err());
+    }
+}
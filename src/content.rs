@@ -0,0 +1,137 @@
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::{Sha256Bytes, compute_file_sha256, is_video};
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+// EXIF and XMP live in APP1, other editors write APP2-APP15 and comments
+const JPEG_APP_MARKERS: std::ops::RangeInclusive<u8> = 0xE0..=0xEF;
+const JPEG_COMMENT_MARKER: u8 = 0xFE;
+const BOX_HEADER_SIZE: u64 = 8;
+const LARGE_BOX_HEADER_SIZE: u64 = 16;
+
+/// Hashes the encoded pixels or media samples, leaving out the metadata editors rewrite.
+/// Formats that can't be split, like HEIC which keeps its EXIF among the image data, fall back
+/// to hashing the whole file.
+pub fn content_hash(path: &Path) -> io::Result<Sha256Bytes> {
+    let is_avi = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("avi"));
+    let hash = if is_avi {
+        // RIFF, not ISO media, its chunks aren't split out
+        None
+    } else if is_video(path) {
+        mp4_media_hash(path)?
+    } else {
+        jpeg_scan_hash(path)?
+    };
+    match hash {
+        Some(hash) => Ok(hash),
+        None => compute_file_sha256(path),
+    }
+}
+
+/// Hashes a JPEG from its first non-metadata segment, the tables and scans that make up the image.
+fn jpeg_scan_hash(path: &Path) -> io::Result<Option<Sha256Bytes>> {
+    let data = fs::read(path)?;
+    if !data.starts_with(&JPEG_SOI) {
+        return Ok(None);
+    }
+    let mut position = JPEG_SOI.len();
+    while position + 4 <= data.len() {
+        if data[position] != 0xFF {
+            return Ok(None);
+        }
+        let marker = data[position + 1];
+        if !JPEG_APP_MARKERS.contains(&marker) && marker != JPEG_COMMENT_MARKER {
+            return Ok(Some(Sha256::digest(&data[position..])));
+        }
+        // The segment length counts itself but not the marker
+        let length = u16::from_be_bytes([data[position + 2], data[position + 3]]) as usize;
+        position += 2 + length;
+    }
+    Ok(None)
+}
+
+/// Hashes the `mdat` boxes of an MP4 or MOV, the metadata lives in `moov` and isn't covered.
+fn mp4_media_hash(path: &Path) -> io::Result<Option<Sha256Bytes>> {
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut hasher = Sha256::new();
+    let mut found = false;
+    let mut position = 0;
+    while position + BOX_HEADER_SIZE <= size {
+        reader.seek(SeekFrom::Start(position))?;
+        let mut header = [0u8; BOX_HEADER_SIZE as usize];
+        reader.read_exact(&mut header)?;
+        let mut header_size = BOX_HEADER_SIZE;
+        let box_size = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // The real size doesn't fit 32 bits and follows the type
+            1 => {
+                let mut large_size = [0u8; 8];
+                reader.read_exact(&mut large_size)?;
+                header_size = LARGE_BOX_HEADER_SIZE;
+                u64::from_be_bytes(large_size)
+            }
+            // The last box extends to the end of the file
+            0 => size - position,
+            box_size => box_size as u64,
+        };
+        // Sizes come from the file, a corrupt one can overflow or point past its end
+        let end = position
+            .checked_add(box_size)
+            .filter(|&end| box_size >= header_size && end <= size)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("box at {} has an invalid size of {}", position, box_size),
+                )
+            })?;
+        if &header[4..] == b"mdat" {
+            hash_bytes(&mut reader, &mut hasher, box_size - header_size)?;
+            found = true;
+        }
+        position = end;
+    }
+    Ok(found.then(|| hasher.finalize()))
+}
+
+fn hash_bytes(reader: &mut impl Read, hasher: &mut Sha256, length: u64) -> io::Result<()> {
+    let mut reader = reader.take(length);
+    let mut buffer = [0u8; 8192];
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflowing_box_size_is_an_error() {
+        let path =
+            std::env::temp_dir().join(format!("rcc-content-test-{}.mp4", std::process::id()));
+        // A valid `ftyp` followed by a 64-bit box size that wraps the position around
+        let data = [
+            &16u32.to_be_bytes()[..],
+            b"ftypisom",
+            &[0; 4],
+            &1u32.to_be_bytes(),
+            b"mdat",
+            &u64::MAX.to_be_bytes(),
+        ]
+        .concat();
+        fs::write(&path, data).unwrap();
+        let result = mp4_media_hash(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
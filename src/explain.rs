@@ -0,0 +1,61 @@
+use anyhow::Result;
+use colored::Colorize;
+use nom_exif::{MediaParser, MediaSource, TrackInfo};
+use rexiv2::Metadata;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use crate::{Cli, REXIV2_AVAILABLE, get_metadata_nom, is_video};
+
+/// Prints everything extracted from a single file along with the raw tags it was read from.
+pub fn explain_file(path: &PathBuf, cli: &Cli) -> Result<()> {
+    let metadata = get_metadata_nom(path, cli)?;
+    log!("{}", path.display());
+    log!("{:#?}", metadata.file_metadata);
+    if let Some(image) = &metadata.image_metadata {
+        log!("{:#?}", image);
+    }
+    if let Some(video) = &metadata.video_metadata {
+        log!("{:#?}", video);
+    }
+    if let Some(digest) = &metadata.file_metadata.digest {
+        log!("Hash: {}", digest);
+    }
+
+    if is_video(path) {
+        print_track_tags(path)
+    } else {
+        print_exif_tags(path)
+    }
+}
+
+fn print_exif_tags(path: &Path) -> Result<()> {
+    if !REXIV2_AVAILABLE.load(Ordering::Relaxed) {
+        log!("{}", "EXIF tags can't be listed without gexiv2".yellow());
+        return Ok(());
+    }
+    let meta = Metadata::new_from_path(path)?;
+    log!("\nEXIF tags:");
+    for tag in meta.get_exif_tags()? {
+        let value = meta
+            .get_tag_interpreted_string(&tag)
+            .unwrap_or_else(|err| format!("<{err}>"));
+        log!("  {}: {}", tag, value);
+    }
+    Ok(())
+}
+
+fn print_track_tags(path: &Path) -> Result<()> {
+    let mut parser = MediaParser::new();
+    let ms = MediaSource::file_path(path)?;
+    if !ms.has_track() {
+        log!("{}", "No track info found".yellow());
+        return Ok(());
+    }
+    let track_info: TrackInfo = parser.parse(ms)?;
+    log!("\nTrack tags:");
+    for (tag, value) in track_info.iter() {
+        log!("  {}: {}", tag, value);
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, Utc};
+use num_rational::Ratio;
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::hashing::{FileDigest, HashAlgorithm};
+use crate::{
+    AudioTrack, CollectedMetadata, DateSource, EXIF_DATE_FORMAT, Entry, FileMetadata,
+    ImageMetadata, VideoMetadata,
+};
+
+const CAMERAS: [(&str, &str); 4] = [
+    ("Apple", "iPhone 13 Pro"),
+    ("Apple", "iPhone 8"),
+    ("SONY", "ILCE-7M3"),
+    ("Canon", "Canon EOS 80D"),
+];
+const RESOLUTIONS: [(i32, i32); 3] = [(4032, 3024), (6000, 4000), (3024, 4032)];
+// Roughly the share of videos in a phone camera roll
+const VIDEO_PERCENT: u64 = 10;
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+
+/// Source and destination entries for benchmarking the comparison phase.
+pub struct FixtureSet {
+    pub src: Vec<Entry>,
+    pub dest: Vec<Entry>,
+}
+
+/// Generates `count` entries on each side, `duplicate_percent` of the sources are copies of a
+/// destination entry. The same seed always produces the same set.
+pub fn generate(count: usize, duplicate_percent: u64, seed: u64) -> FixtureSet {
+    let mut rng = XorShift(seed.max(1));
+    let dest: Vec<Entry> = (0..count)
+        .map(|index| synthetic_entry(&mut rng, index, true))
+        .collect();
+    let src = (0..count)
+        .map(|index| {
+            if rng.below(100) < duplicate_percent {
+                let original = &dest[rng.below(count as u64) as usize];
+                copy_entry(original)
+            } else {
+                synthetic_entry(&mut rng, count + index, false)
+            }
+        })
+        .collect();
+    FixtureSet { src, dest }
+}
+
+fn synthetic_entry(rng: &mut XorShift, index: usize, is_dest: bool) -> Entry {
+    let is_video = rng.below(100) < VIDEO_PERCENT;
+    let extension = if is_video { "mov" } else { "heic" };
+    let base_file_name = format!("IMG_{:04}.{}", index % 10_000, extension);
+    let root = PathBuf::from(if is_dest { "/dest" } else { "/src" });
+    let path = root.join(format!("{}/{}", index / 10_000, base_file_name));
+
+    // Captures cluster in time, so many files share the same day
+    let taken = NaiveDate::from_ymd_opt(2018, 1, 1)
+        .unwrap()
+        .and_hms_opt(0, 0, 0)
+        .unwrap()
+        + ChronoDuration::seconds(rng.below(5 * SECONDS_PER_YEAR) as i64);
+    let date = taken.format(EXIF_DATE_FORMAT).to_string();
+    let file_size = if is_video {
+        10_000_000 + rng.below(500_000_000)
+    } else {
+        1_000_000 + rng.below(4_000_000)
+    };
+
+    let (image_metadata, video_metadata) = if is_video {
+        let video = VideoMetadata {
+            date,
+            video_duration: Some(Duration::from_millis(1_000 + rng.below(120_000))),
+            audio: AudioTrack::Unknown,
+        };
+        (None, Some(video))
+    } else {
+        let (make, model) = CAMERAS[rng.below(CAMERAS.len() as u64) as usize];
+        let (width, height) = RESOLUTIONS[rng.below(RESOLUTIONS.len() as u64) as usize];
+        let image = ImageMetadata {
+            date,
+            date_source: DateSource::Original,
+            resolution: Some((Ratio::from_integer(width), Ratio::from_integer(height))),
+            // Older cameras don't write sub-second tags
+            sub_sec: (rng.below(2) == 0).then(|| format!("{:03}", rng.below(1000))),
+            make: Some(make.to_string()),
+            model: Some(model.to_string()),
+            lens: None,
+            brightness: Some(Ratio::new(rng.below(2000) as i32 - 1000, 100)),
+            gps: None,
+            thumbnail_hash: Some(Sha256::digest(rng.next_u64().to_le_bytes())),
+        };
+        (Some(image), None)
+    };
+
+    let metadata = CollectedMetadata {
+        file_metadata: FileMetadata {
+            base_file_name,
+            file_size,
+            extension: extension.to_string(),
+            creation_date: DateTime::<Utc>::from_timestamp(taken.and_utc().timestamp(), 0),
+            edit_sidecar: false,
+            sample_hash: None,
+            digest: Some(FileDigest {
+                algorithm: HashAlgorithm::Xxh3,
+                bytes: rng.next_u64().to_le_bytes().to_vec(),
+            }),
+        },
+        image_metadata,
+        video_metadata,
+        content_hash: None,
+        perceptual_hash: None,
+    };
+    Entry {
+        canonical_path: path.clone(),
+        path,
+        root,
+        metadata,
+        is_dest,
+    }
+}
+
+fn copy_entry(original: &Entry) -> Entry {
+    let root = PathBuf::from("/src");
+    let path = root.join(original.path.strip_prefix(&original.root).unwrap());
+    Entry {
+        canonical_path: path.clone(),
+        path,
+        root,
+        metadata: original.metadata.clone(),
+        is_dest: false,
+    }
+}
+
+/// Small deterministic generator, the fixtures don't need statistical quality.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+}
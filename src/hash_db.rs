@@ -0,0 +1,38 @@
+use anyhow::Result;
+use rusqlite::{Connection, OpenFlags, OptionalExtension};
+use std::path::Path;
+
+/// Read-only view of an external SQLite database mapping SHA-256 hashes to archived paths.
+pub struct HashDb {
+    connection: Connection,
+    query: String,
+}
+
+impl HashDb {
+    pub fn open(path: &Path, table: &str, hash_column: &str, path_column: &str) -> Result<HashDb> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        // Hashes may be stored in either case, matching both keeps an index on the column usable
+        let query = format!(
+            "SELECT {} FROM {} WHERE {} IN (?1, ?2) LIMIT 1",
+            quote_identifier(path_column),
+            quote_identifier(table),
+            quote_identifier(hash_column)
+        );
+        // Fail early on a wrong schema instead of on the first lookup
+        connection.prepare(&query)?;
+        Ok(HashDb { connection, query })
+    }
+
+    /// Returns the archived path of a file with the given hex SHA-256, if there is one.
+    pub fn lookup(&self, hash: &str) -> Result<Option<String>> {
+        let mut statement = self.connection.prepare_cached(&self.query)?;
+        let path = statement
+            .query_row((hash.to_lowercase(), hash.to_uppercase()), |row| row.get(0))
+            .optional()?;
+        Ok(path)
+    }
+}
+
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
@@ -0,0 +1,80 @@
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use xxhash_rust::xxh3::Xxh3;
+
+/// Algorithm for hashing whole files. Duplicate detection only has to survive accidental
+/// collisions, so the much faster non-cryptographic XXH3 is the default.
+#[derive(Default, PartialEq, Eq, Clone, Copy, Debug, ValueEnum)]
+pub enum HashAlgorithm {
+    Sha256,
+    Blake3,
+    #[default]
+    Xxh3,
+}
+
+impl Display for HashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HashAlgorithm::Sha256 => f.write_str("sha256"),
+            HashAlgorithm::Blake3 => f.write_str("blake3"),
+            HashAlgorithm::Xxh3 => f.write_str("xxh3"),
+        }
+    }
+}
+
+/// Hash of a file's full contents along with the algorithm that produced it, so digests from
+/// different algorithms are never mistaken for each other.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct FileDigest {
+    pub algorithm: HashAlgorithm,
+    pub bytes: Vec<u8>,
+}
+
+impl Display for FileDigest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}:", self.algorithm))?;
+        for byte in &self.bytes {
+            f.write_fmt(format_args!("{:02x}", byte))?;
+        }
+        Ok(())
+    }
+}
+
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> io::Result<FileDigest> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let bytes = match algorithm {
+        HashAlgorithm::Sha256 => {
+            let mut hasher = Sha256::new();
+            for_each_chunk(&mut reader, |chunk| hasher.update(chunk))?;
+            hasher.finalize().to_vec()
+        }
+        HashAlgorithm::Blake3 => {
+            let mut hasher = blake3::Hasher::new();
+            for_each_chunk(&mut reader, |chunk| {
+                hasher.update(chunk);
+            })?;
+            hasher.finalize().as_bytes().to_vec()
+        }
+        HashAlgorithm::Xxh3 => {
+            let mut hasher = Xxh3::new();
+            for_each_chunk(&mut reader, |chunk| hasher.update(chunk))?;
+            hasher.digest128().to_be_bytes().to_vec()
+        }
+    };
+    Ok(FileDigest { algorithm, bytes })
+}
+
+fn for_each_chunk(reader: &mut impl Read, mut consume: impl FnMut(&[u8])) -> io::Result<()> {
+    let mut buffer = [0u8; 8192];
+    loop {
+        let bytes_read = reader.read(&mut buffer)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        consume(&buffer[..bytes_read]);
+    }
+}
@@ -0,0 +1,48 @@
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::{compute_file_sha256, hex_digest};
+
+const SHA256_HEX_LEN: usize = 64;
+
+/// Files that must never be acted on, listed either by path or by SHA-256 of their contents.
+#[derive(Default)]
+pub struct IgnoreList {
+    paths: HashSet<PathBuf>,
+    hashes: HashSet<String>,
+}
+
+impl IgnoreList {
+    pub fn load(path: &Path) -> Result<IgnoreList> {
+        let mut ignore_list = IgnoreList::default();
+        for line in fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.len() == SHA256_HEX_LEN && line.chars().all(|c| c.is_ascii_hexdigit()) {
+                ignore_list.hashes.insert(line.to_lowercase());
+            } else {
+                ignore_list.paths.insert(canonical(Path::new(line)));
+            }
+        }
+        Ok(ignore_list)
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        if self.paths.contains(&canonical(path)) {
+            return true;
+        }
+        if self.hashes.is_empty() {
+            return false;
+        }
+        // Only hash files that are about to be acted on
+        compute_file_sha256(path).is_ok_and(|hash| self.hashes.contains(&hex_digest(&hash)))
+    }
+}
+
+fn canonical(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or(path.to_path_buf())
+}
@@ -0,0 +1,1360 @@
+//! Duplicate detection for photos and videos, shared by the `rcc` binary and the benchmarks.
+//! Entries are matched lazily, `find_duplicates` yields each group as soon as it is found.
+
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use clap::ValueEnum;
+use num_rational::Ratio;
+use rayon::prelude::*;
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fmt::{Debug, Display};
+use std::fs;
+use std::ops::{Mul, Sub};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use hashing::FileDigest;
+
+/// Set when stdout carries machine-readable output, messages then go to stderr.
+pub static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Extensions from `--image-ext` and `--video-ext`, recognized on top of the built-in ones.
+pub static EXTRA_IMAGE_EXTENSIONS: OnceLock<Vec<String>> = OnceLock::new();
+pub static EXTRA_VIDEO_EXTENSIONS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Patterns from `--copy-suffixes`, `DEFAULT_COPY_SUFFIXES` when not given.
+pub static COPY_SUFFIXES: OnceLock<Vec<String>> = OnceLock::new();
+
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if $crate::LOG_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
+pub mod hashing;
+// Synthetic entries for the benchmarks in benches/
+#[cfg(any(test, feature = "bench"))]
+pub mod fixtures;
+pub mod trace;
+
+const IMAGE_EXTENSIONS: [&str; 3] = ["heic", "jpg", "jpeg"];
+const VIDEOS_EXTENSIONS: [&str; 3] = ["mov", "mp4", "avi"];
+
+// Decorations file managers add to copies, longer ones first so ` - copy` isn't cut to ` -`.
+// `#` stands for the copy number
+const DEFAULT_COPY_SUFFIXES: [&str; 7] =
+    [" (#)", "(#)", " - copy", "-copy", " copy #", " copy", "_#"];
+// Longer numbers are sequence numbers, e.g. the 1234 in `IMG_1234`, not copy counters
+const MAX_COPY_NUMBER_DIGITS: usize = 2;
+
+// Destination entries compared in parallel before their results are handed out
+const COMPARE_CHUNK_SIZE: usize = 256;
+
+// In APEX EV, well below the difference between two real exposures
+const BRIGHTNESS_TOLERANCE: f32 = 0.01;
+// Near misses agreeing on fewer fields than this are too different to be worth listing
+const NEAR_MISS_THRESHOLD: f32 = 0.5;
+// Re-muxing can pad or trim the audio by a few frames
+const AUDIO_DURATION_TOLERANCE: Duration = Duration::from_millis(500);
+// Millionths of a degree, about 10 meters. Editors round coordinates when rewriting them
+const GPS_TOLERANCE: u32 = 100;
+// Remuxing can drop or pad a partial frame at either end
+const REMUX_DURATION_TOLERANCE: Duration = Duration::from_millis(100);
+// A clip is dated when recording starts, the photo taken alongside it a moment later
+const CROSS_MEDIA_DATE_TOLERANCE: Duration = Duration::from_secs(2);
+
+pub const EXIF_DATE_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+
+pub const AVI_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+pub const FS_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
+
+pub type Sha256Bytes = sha2::digest::Output<Sha256>;
+
+#[derive(Default, Clone, Eq, PartialEq, Debug)]
+pub struct CollectedMetadata {
+    pub file_metadata: FileMetadata,
+    pub image_metadata: Option<ImageMetadata>,
+    pub video_metadata: Option<VideoMetadata>,
+    pub content_hash: Option<Sha256Bytes>,
+    // Difference hash of the pixels, only computed with `--hamming`
+    pub perceptual_hash: Option<u64>,
+}
+
+impl CollectedMetadata {
+    fn has_rich_metadata(&self) -> bool {
+        self.image_metadata.is_some() || self.video_metadata.is_some()
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, ValueEnum)]
+pub enum MissingMetadata {
+    Reject,
+    Hash,
+}
+
+#[derive(PartialEq, Clone, Copy, ValueEnum)]
+pub enum CompareMode {
+    Loose,
+    Paranoid,
+    Exact,
+}
+
+impl Display for CompareMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompareMode::Loose => f.write_str("loose"),
+            CompareMode::Paranoid => f.write_str("paranoid"),
+            CompareMode::Exact => f.write_str("exact"),
+        }
+    }
+}
+
+impl CompareMode {
+    /// Exact builds on top of the paranoid checks.
+    fn is_strict(&self) -> bool {
+        matches!(self, CompareMode::Paranoid | CompareMode::Exact)
+    }
+}
+
+/// A scanned file with the metadata it is compared by.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Entry {
+    pub path: PathBuf,
+    // Resolves symlinks and `..`, used to tell whether two entries are the same file
+    pub canonical_path: PathBuf,
+    pub root: PathBuf,
+    pub metadata: CollectedMetadata,
+    pub is_dest: bool,
+}
+
+impl Entry {
+    pub fn new(path: PathBuf, root: PathBuf, metadata: CollectedMetadata, is_dest: bool) -> Entry {
+        let canonical_path = fs::canonicalize(&path).unwrap_or(path.clone());
+        Entry {
+            path,
+            canonical_path,
+            root,
+            metadata,
+            is_dest,
+        }
+    }
+}
+
+pub fn hex_digest(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("dest: {}", self.is_dest))?;
+        f.write_fmt(format_args!(" p: {:?}", self.path))?;
+        f.write_fmt(format_args!(" m: {}", self.metadata))?;
+        Ok(())
+    }
+}
+
+impl Display for CollectedMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("f: {}", self.file_metadata))?;
+        if let Some(meta) = self.image_metadata.clone() {
+            f.write_fmt(format_args!(" i: {}", meta))?;
+        }
+        if let Some(meta) = self.video_metadata.clone() {
+            f.write_fmt(format_args!(" v: {}", meta))?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for FileMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("base: {}", self.base_file_name))?;
+        f.write_fmt(format_args!(" s: {}", self.file_size))?;
+        f.write_fmt(format_args!(" e: {}", self.extension))?;
+        if let Some(date) = self.creation_date {
+            f.write_fmt(format_args!(" d: {}", date.format(FS_DATE_FORMAT)))?;
+        }
+        if self.edit_sidecar {
+            f.write_str(" edited")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for VideoMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("date: {}", self.date))?;
+        if let Some(duration) = self.video_duration.clone() {
+            f.write_fmt(format_args!(" d: {:?}", duration))?;
+        }
+        match self.audio {
+            AudioTrack::Unknown => {}
+            AudioTrack::Silent => f.write_str(" silent")?,
+            AudioTrack::Present { duration, .. } => {
+                f.write_fmt(format_args!(" a: {:?}", duration))?
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for ImageMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("date: {}", self.date))?;
+        if let Some(sub_sec) = self.sub_sec.clone() {
+            f.write_fmt(format_args!(".{}", sub_sec))?;
+        }
+        if self.date_source != DateSource::Original {
+            f.write_fmt(format_args!(" ({})", self.date_source))?;
+        }
+        if let Some(make) = self.make.clone() {
+            f.write_fmt(format_args!(" {}", make))?;
+        }
+        if let Some(model) = self.model.clone() {
+            f.write_fmt(format_args!(" {}", model))?;
+        }
+        if let Some(lens) = self.lens.clone() {
+            f.write_fmt(format_args!(" l: {}", lens))?;
+        }
+        if let Some((x, y)) = self.resolution.clone() {
+            f.write_fmt(format_args!(" {}x{}", x, y))?;
+        }
+        if let Some(brightness) = self.brightness {
+            f.write_fmt(format_args!(" b: {}", brightness))?;
+        }
+        if let Some(gps) = self.gps {
+            f.write_fmt(format_args!(
+                " gps: {:.6},{:.6}",
+                gps.latitude as f64 / 1_000_000.0,
+                gps.longitude as f64 / 1_000_000.0
+            ))?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the capture date of an image was read from, scans and imports often lack the original.
+#[derive(Default, Clone, Copy, Eq, PartialEq, Debug)]
+pub enum DateSource {
+    #[default]
+    Original,
+    Digitized,
+    Modified,
+    FileTime,
+}
+
+impl Display for DateSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DateSource::Original => f.write_str("original"),
+            DateSource::Digitized => f.write_str("digitized"),
+            DateSource::Modified => f.write_str("modified"),
+            DateSource::FileTime => f.write_str("file time"),
+        }
+    }
+}
+
+/// Position in millionths of a degree, keeping ImageMetadata comparable without floats.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub struct GpsPosition {
+    pub latitude: i32,
+    pub longitude: i32,
+}
+
+impl GpsPosition {
+    pub fn from_degrees(latitude: f64, longitude: f64) -> GpsPosition {
+        GpsPosition {
+            latitude: (latitude * 1_000_000.0).round() as i32,
+            longitude: (longitude * 1_000_000.0).round() as i32,
+        }
+    }
+
+    fn is_near(&self, other: &GpsPosition) -> bool {
+        self.latitude.abs_diff(other.latitude) <= GPS_TOLERANCE
+            && self.longitude.abs_diff(other.longitude) <= GPS_TOLERANCE
+    }
+}
+
+#[derive(Default, Clone, Eq, PartialEq, Debug)]
+pub struct ImageMetadata {
+    pub date: String,
+    pub date_source: DateSource,
+    pub resolution: Option<(Ratio<i32>, Ratio<i32>)>,
+    pub sub_sec: Option<String>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub lens: Option<String>,
+    pub brightness: Option<Ratio<i32>>,
+    pub gps: Option<GpsPosition>,
+    pub thumbnail_hash: Option<Sha256Bytes>,
+}
+
+#[derive(Default, Clone, Eq, PartialEq, Debug)]
+pub struct FileMetadata {
+    pub base_file_name: String,
+    pub file_size: u64,
+    // Lowercased for matching only, scripts must use the entry path to keep the original case
+    pub extension: String,
+    pub creation_date: Option<DateTime<Utc>>,
+    pub edit_sidecar: bool,
+    pub sample_hash: Option<Sha256Bytes>,
+    // Only computed in exact mode or for files without readable metadata
+    pub digest: Option<FileDigest>,
+}
+
+/// Subset of the CLI options that affect how entries are compared and reported.
+#[derive(Clone, Copy)]
+pub struct CompareSettings {
+    pub mode: CompareMode,
+    pub thumb_hash: bool,
+    pub size_tolerance: f32,
+    pub resolution_tolerance: Option<f32>,
+    pub ignore_extension: bool,
+    pub fs_date_tolerance: Option<Duration>,
+    pub same_relative_path: bool,
+    pub missing_metadata: MissingMetadata,
+    pub compare_audio: bool,
+    pub require_gps_match: bool,
+    pub strict_fields: bool,
+    pub content_wins: bool,
+    pub hamming: Option<u32>,
+    pub match_remuxes: bool,
+    pub assume_tz: Option<FixedOffset>,
+    pub verbose: bool,
+}
+
+impl CompareSettings {
+    /// Settings matching the CLI defaults for the given mode.
+    pub fn new(mode: CompareMode) -> CompareSettings {
+        CompareSettings {
+            mode,
+            thumb_hash: false,
+            size_tolerance: 0.01,
+            resolution_tolerance: None,
+            ignore_extension: false,
+            fs_date_tolerance: None,
+            same_relative_path: false,
+            missing_metadata: MissingMetadata::Reject,
+            compare_audio: false,
+            require_gps_match: false,
+            strict_fields: false,
+            content_wins: false,
+            hamming: None,
+            match_remuxes: false,
+            assume_tz: None,
+            verbose: false,
+        }
+    }
+}
+
+trait CompareMetadata<T> {
+    fn metadata_matches(a: &T, b: &T, settings: &CompareSettings) -> bool;
+}
+
+impl CompareMetadata<FileDigest> for FileDigest {
+    fn metadata_matches(a: &FileDigest, b: &FileDigest, _settings: &CompareSettings) -> bool {
+        a == b
+    }
+}
+
+/// Audio of a video, tells an original apart from a muted or re-encoded export.
+#[derive(Default, Clone, Copy, Eq, PartialEq, Debug)]
+pub enum AudioTrack {
+    // The container couldn't be inspected, e.g. an AVI or an unreadable MOV
+    #[default]
+    Unknown,
+    Silent,
+    Present {
+        duration: Duration,
+        sample_rate: Option<u32>,
+    },
+}
+
+#[derive(Default, Clone, Eq, PartialEq, Debug)]
+pub struct VideoMetadata {
+    pub date: String,
+    pub video_duration: Option<Duration>,
+    pub audio: AudioTrack,
+}
+
+impl CompareMetadata<VideoMetadata> for VideoMetadata {
+    fn metadata_matches(a: &VideoMetadata, b: &VideoMetadata, settings: &CompareSettings) -> bool {
+        if settings.match_remuxes || settings.assume_tz.is_some() {
+            // Each container reader formats the date differently, compare the instants instead
+            match (
+                parse_video_date(&a.date, settings.assume_tz),
+                parse_video_date(&b.date, settings.assume_tz),
+            ) {
+                (Some(a_date), Some(b_date)) => {
+                    if a_date != b_date {
+                        return false;
+                    }
+                }
+                _ => {
+                    if a.date != b.date {
+                        return false;
+                    }
+                }
+            }
+        } else if a.date != b.date {
+            return false;
+        }
+
+        if settings.match_remuxes
+            && let (Some(a_duration), Some(b_duration)) = (a.video_duration, b.video_duration)
+        {
+            if a_duration.abs_diff(b_duration) > REMUX_DURATION_TOLERANCE {
+                return false;
+            }
+        } else if let Some(duration) = compare_if_exist(&a.video_duration, &b.video_duration) {
+            if !duration {
+                return false;
+            }
+        } else {
+            if settings.mode.is_strict() {
+                return false;
+            }
+        }
+
+        if settings.compare_audio && !audio_matches(a.audio, b.audio, settings.strict_fields) {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Reads the dates written by the different video readers: RFC 3339 from nom-exif, UTC from
+/// the mp4 crate and local time from AVI headers. Local times are taken to be in `local_offset`,
+/// or treated as UTC when it isn't known.
+pub fn parse_video_date(date: &str, local_offset: Option<FixedOffset>) -> Option<DateTime<Utc>> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Some(date.to_utc());
+    }
+    if let Ok(date) = NaiveDateTime::parse_from_str(date, FS_DATE_FORMAT) {
+        return Some(date.and_utc());
+    }
+    let date = NaiveDateTime::parse_from_str(date, AVI_DATE_FORMAT).ok()?;
+    match local_offset {
+        Some(offset) => date
+            .and_local_timezone(offset)
+            .single()
+            .map(|date| date.to_utc()),
+        None => Some(date.and_utc()),
+    }
+}
+
+/// Reads an EXIF date, which is local time without a timezone. It is taken to be in
+/// `local_offset`, or treated as UTC when it isn't known.
+pub fn parse_image_date(date: &str, local_offset: Option<FixedOffset>) -> Option<DateTime<Utc>> {
+    let date = NaiveDateTime::parse_from_str(date, EXIF_DATE_FORMAT).ok()?;
+    match local_offset {
+        Some(offset) => date
+            .and_local_timezone(offset)
+            .single()
+            .map(|date| date.to_utc()),
+        None => Some(date.and_utc()),
+    }
+}
+
+/// Whether a photo and a video were taken at the same moment, `None` unless one entry is only
+/// a photo and the other only a video.
+fn cross_media_dates_agree(
+    a: &CollectedMetadata,
+    b: &CollectedMetadata,
+    local_offset: Option<FixedOffset>,
+) -> Option<bool> {
+    let (image, video) = match (a, b) {
+        (
+            CollectedMetadata {
+                image_metadata: Some(image),
+                video_metadata: None,
+                ..
+            },
+            CollectedMetadata {
+                image_metadata: None,
+                video_metadata: Some(video),
+                ..
+            },
+        )
+        | (
+            CollectedMetadata {
+                image_metadata: None,
+                video_metadata: Some(video),
+                ..
+            },
+            CollectedMetadata {
+                image_metadata: Some(image),
+                video_metadata: None,
+                ..
+            },
+        ) => (image, video),
+        _ => return None,
+    };
+    let image_date = parse_image_date(&image.date, local_offset)?;
+    let video_date = parse_video_date(&video.date, local_offset)?;
+    let diff = (image_date - video_date).abs().to_std().unwrap_or_default();
+    Some(diff <= CROSS_MEDIA_DATE_TOLERANCE)
+}
+
+fn audio_matches(a: AudioTrack, b: AudioTrack, strict_fields: bool) -> bool {
+    match (a, b) {
+        (AudioTrack::Unknown, AudioTrack::Unknown) => true,
+        (AudioTrack::Unknown, _) | (_, AudioTrack::Unknown) => !strict_fields,
+        (AudioTrack::Silent, AudioTrack::Silent) => true,
+        (
+            AudioTrack::Present {
+                duration: a_duration,
+                sample_rate: a_rate,
+            },
+            AudioTrack::Present {
+                duration: b_duration,
+                sample_rate: b_rate,
+            },
+        ) => {
+            a_duration.abs_diff(b_duration) <= AUDIO_DURATION_TOLERANCE
+                && compare_if_exist(&a_rate, &b_rate) != Some(false)
+        }
+        _ => false,
+    }
+}
+
+impl CompareMetadata<ImageMetadata> for ImageMetadata {
+    fn metadata_matches(a: &ImageMetadata, b: &ImageMetadata, settings: &CompareSettings) -> bool {
+        if a.date != b.date {
+            return false;
+        }
+
+        // Burst frames can share the same second, sub-second tags tell them apart.
+        // Copies often lose the tag, so it's only compared when both files have it
+        if let (Some(a_sub_sec), Some(b_sub_sec)) = (&a.sub_sec, &b.sub_sec)
+            && a_sub_sec != b_sub_sec
+        {
+            return false;
+        }
+        if settings.strict_fields && presence_differs(&a.sub_sec, &b.sub_sec) {
+            return false;
+        }
+
+        if let Some(make) = compare_if_exist(&a.make, &b.make)
+            && !make
+        {
+            return false;
+        }
+
+        if let Some(model) = compare_if_exist(&a.model, &b.model)
+            && !model
+        {
+            return false;
+        }
+
+        if let Some(lens) = compare_if_exist(&a.lens, &b.lens)
+            && !lens
+        {
+            return false;
+        }
+
+        // Rewritten metadata can store the same exposure with a different denominator
+        match (
+            a.brightness.and_then(ratio_to_f32),
+            b.brightness.and_then(ratio_to_f32),
+        ) {
+            (Some(a_brightness), Some(b_brightness)) => {
+                if (a_brightness - b_brightness).abs() > BRIGHTNESS_TOLERANCE {
+                    return false;
+                }
+            }
+            (None, None) => {}
+            _ => return false,
+        }
+
+        // Copies shared online often have the location stripped, so a missing position only
+        // counts against a match when asked for
+        match (a.gps, b.gps) {
+            (Some(a_gps), Some(b_gps)) => {
+                if !a_gps.is_near(&b_gps) {
+                    return false;
+                }
+            }
+            (None, None) => {}
+            _ => {
+                if settings.require_gps_match || settings.strict_fields {
+                    return false;
+                }
+            }
+        }
+
+        if let Some(tolerance) = settings.resolution_tolerance
+            && let (Some(a_res), Some(b_res)) = (a.resolution, b.resolution)
+        {
+            return compare_with_tolerance(total_pixels(a_res), total_pixels(b_res), tolerance);
+        }
+        if let Some(resolution) = compare_if_exist(&a.resolution, &b.resolution)
+            && !resolution
+        {
+            return false;
+        }
+        true
+    }
+}
+
+impl CompareMetadata<FileMetadata> for FileMetadata {
+    fn metadata_matches(a: &FileMetadata, b: &FileMetadata, settings: &CompareSettings) -> bool {
+        // Remuxed videos keep the footage but change the container and extension
+        let ignore_extension = settings.ignore_extension
+            || (settings.match_remuxes
+                && is_video_extension(&a.extension)
+                && is_video_extension(&b.extension));
+        if settings.mode.is_strict() {
+            // Copies often get a decorated name like `IMG_1234 (1).jpg`
+            let names_match = undecorated_stem(&a.base_file_name)
+                == undecorated_stem(&b.base_file_name)
+                && (ignore_extension
+                    || Path::new(&a.base_file_name).extension()
+                        == Path::new(&b.base_file_name).extension());
+            if !names_match {
+                return false;
+            }
+        }
+
+        // An edited original renders differently than an untouched copy
+        if settings.mode.is_strict() && a.edit_sidecar != b.edit_sidecar {
+            return false;
+        }
+
+        // Checked on the names as they are, the copy numbers stripped above can tell frames apart
+        if sequence_numbers_differ(&a.base_file_name, &b.base_file_name) {
+            return false;
+        }
+
+        if let Some(tolerance) = settings.fs_date_tolerance {
+            // Without a birth time on both sides the dates can't confirm anything
+            let (Some(a_date), Some(b_date)) = (a.creation_date, b.creation_date) else {
+                return false;
+            };
+            let diff = (a_date - b_date).abs().to_std().unwrap_or_default();
+            if diff > tolerance {
+                return false;
+            }
+        }
+
+        // A converted file has different contents, only its metadata can be compared
+        if ignore_extension && a.extension != b.extension {
+            return true;
+        }
+
+        if !compare_with_tolerance(
+            a.file_size as f32,
+            b.file_size as f32,
+            settings.size_tolerance,
+        ) {
+            // println!("mismatch on size");
+            return false;
+        }
+
+        if a.extension != b.extension {
+            // println!("mismatch on extension");
+            return false;
+        }
+
+        if let Some(sample_hash) = compare_if_exist(&a.sample_hash, &b.sample_hash)
+            && !sample_hash
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Splits a file stem into its numbers, each with the text leading up to it, e.g. `IMG_0001_2`
+/// into `("IMG_", 1)` and `("_", 2)`.
+fn sequence_numbers(stem: &str) -> Vec<(&str, u64)> {
+    let mut numbers = vec![];
+    let mut rest = stem;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_digit()) {
+        let digits = &rest[start..];
+        let end = digits
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(digits.len());
+        if let Ok(number) = digits[..end].parse() {
+            numbers.push((&rest[..start], number));
+        }
+        rest = &digits[end..];
+    }
+    numbers
+}
+
+/// File name without its extension and the decorations `--copy-suffixes` describes, e.g.
+/// `IMG_1234` for `IMG_1234 (1).jpg` and `IMG_1234 - Copy (2).jpg`.
+fn undecorated_stem(file_name: &str) -> &str {
+    let mut stem = Path::new(file_name)
+        .file_stem()
+        .and_then(OsStr::to_str)
+        .unwrap_or(file_name);
+    let suffixes = COPY_SUFFIXES.get_or_init(|| DEFAULT_COPY_SUFFIXES.map(String::from).to_vec());
+    // Copies of copies pile up decorations
+    while let Some(shorter) = suffixes
+        .iter()
+        .find_map(|suffix| strip_copy_suffix(stem, suffix))
+    {
+        stem = shorter;
+    }
+    stem
+}
+
+/// Removes `pattern` from the end of `stem`, letters are compared case-insensitively and `#`
+/// matches a copy number. A name that is nothing but the decoration is left alone.
+fn strip_copy_suffix<'a>(stem: &'a str, pattern: &str) -> Option<&'a str> {
+    let mut rest = stem;
+    for expected in pattern.chars().rev() {
+        if expected == '#' {
+            let digits = rest.len() - rest.trim_end_matches(|c: char| c.is_ascii_digit()).len();
+            if digits == 0 || digits > MAX_COPY_NUMBER_DIGITS {
+                return None;
+            }
+            rest = &rest[..rest.len() - digits];
+        } else {
+            let last = rest.chars().next_back()?;
+            if !last.to_lowercase().eq(expected.to_lowercase()) {
+                return None;
+            }
+            rest = &rest[..rest.len() - last.len_utf8()];
+        }
+    }
+    (!rest.is_empty()).then_some(rest)
+}
+
+/// Detects consecutive frames of a burst, e.g. `IMG_1234` and `IMG_1235`, or `IMG_0001_1` and
+/// `IMG_0001_2`. Numbers are compared while the names line up, so `IMG_1234_2` still matches
+/// `IMG_1234`, which has no second number.
+fn sequence_numbers_differ(a: &str, b: &str) -> bool {
+    let stem = |name| {
+        Path::new(name)
+            .file_stem()
+            .and_then(OsStr::to_str)
+            .unwrap_or(name)
+    };
+    sequence_numbers(stem(a))
+        .into_iter()
+        .zip(sequence_numbers(stem(b)))
+        .take_while(|((prefix_a, _), (prefix_b, _))| prefix_a == prefix_b)
+        .any(|((_, number_a), (_, number_b))| number_a != number_b)
+}
+
+/// Name of the first check that tells the entries apart, `None` when they match.
+fn rejecting_check(a: &Entry, b: &Entry, settings: &CompareSettings) -> Option<&'static str> {
+    // Backup verification only pairs up files at the same place under their roots
+    if settings.same_relative_path {
+        let (Some(a_relative), Some(b_relative)) = (relative_path(a), relative_path(b)) else {
+            return Some("relative_path");
+        };
+        if a_relative != b_relative {
+            return Some("relative_path");
+        }
+    }
+
+    // Editors rewrite tags without touching the pixels, identical content settles it
+    if settings.content_wins
+        && let (Some(a_hash), Some(b_hash)) = (&a.metadata.content_hash, &b.metadata.content_hash)
+        && a_hash == b_hash
+    {
+        return None;
+    }
+
+    // Resized or recompressed copies keep nearly the same pixels, close enough settles it too
+    if let Some(max_distance) = settings.hamming
+        && let (Some(a_hash), Some(b_hash)) =
+            (a.metadata.perceptual_hash, b.metadata.perceptual_hash)
+        && hamming_distance(a_hash, b_hash) <= max_distance
+    {
+        return None;
+    }
+
+    // Cheap pre-filter, different embedded thumbnails mean different captures
+    if settings.thumb_hash
+        && let (Some(a), Some(b)) = (&a.metadata.image_metadata, &b.metadata.image_metadata)
+        && let (Some(a_hash), Some(b_hash)) = (&a.thumbnail_hash, &b.thumbnail_hash)
+        && a_hash != b_hash
+    {
+        return Some("thumbnail");
+    }
+    if settings.thumb_hash
+        && settings.strict_fields
+        && let (Some(a), Some(b)) = (&a.metadata.image_metadata, &b.metadata.image_metadata)
+        && presence_differs(&a.thumbnail_hash, &b.thumbnail_hash)
+    {
+        return Some("thumbnail");
+    }
+
+    if settings.mode == CompareMode::Exact {
+        let (Some(a), Some(b)) = (
+            &a.metadata.file_metadata.digest,
+            &b.metadata.file_metadata.digest,
+        ) else {
+            return Some("hash");
+        };
+        if !FileDigest::metadata_matches(a, b, settings) {
+            return Some("hash");
+        }
+    }
+    if !FileMetadata::metadata_matches(
+        &a.metadata.file_metadata,
+        &b.metadata.file_metadata,
+        settings,
+    ) {
+        return Some("file");
+    }
+    // Otherwise a file whose tags couldn't be read is compared on whatever the other side shares
+    if settings.strict_fields {
+        if presence_differs(&a.metadata.image_metadata, &b.metadata.image_metadata) {
+            return Some("image");
+        }
+        if presence_differs(&a.metadata.video_metadata, &b.metadata.video_metadata) {
+            return Some("video");
+        }
+    }
+    let mut metadata_checked = false;
+    if let (Some(a), Some(b)) = (&a.metadata.image_metadata, &b.metadata.image_metadata) {
+        if !ImageMetadata::metadata_matches(a, b, settings) {
+            return Some("image");
+        }
+        metadata_checked = true;
+    }
+    if let (Some(a), Some(b)) = (&a.metadata.video_metadata, &b.metadata.video_metadata) {
+        if !VideoMetadata::metadata_matches(a, b, settings) {
+            return Some("video");
+        }
+        metadata_checked = true;
+    }
+    // A photo and a clip of the same moment only share their date, which can only be compared
+    // once the local EXIF date is placed in a timezone
+    if !metadata_checked
+        && settings.assume_tz.is_some()
+        && let Some(agreed) = cross_media_dates_agree(&a.metadata, &b.metadata, settings.assume_tz)
+    {
+        if !agreed {
+            return Some("date");
+        }
+        metadata_checked = true;
+    }
+
+    if settings.missing_metadata == MissingMetadata::Hash
+        && !a.metadata.has_rich_metadata()
+        && !b.metadata.has_rich_metadata()
+        && let (Some(a), Some(b)) = (
+            &a.metadata.file_metadata.digest,
+            &b.metadata.file_metadata.digest,
+        )
+    {
+        return (a != b).then_some("hash");
+    }
+
+    if !metadata_checked {
+        return Some("missing_metadata");
+    }
+    None
+}
+
+pub fn relative_path(entry: &Entry) -> Option<&Path> {
+    entry.path.strip_prefix(&entry.root).ok()
+}
+
+pub fn total_pixels((x, y): (Ratio<i32>, Ratio<i32>)) -> f32 {
+    let to_f32 = |r: Ratio<i32>| *r.numer() as f32 / *r.denom() as f32;
+    to_f32(x) * to_f32(y)
+}
+
+/// Converts a rational EXIF value, skipping malformed ones with a zero denominator.
+fn ratio_to_f32(ratio: Ratio<i32>) -> Option<f32> {
+    if *ratio.denom() == 0 {
+        return None;
+    }
+    Some(*ratio.numer() as f32 / *ratio.denom() as f32)
+}
+
+fn compare_if_exist<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> Option<bool> {
+    match (a, b) {
+        (Some(a_val), Some(b_val)) => return Some(a_val == b_val),
+        (None, None) => None,
+        (None, Some(_)) => Some(false),
+        (Some(_), None) => Some(false),
+    }
+}
+
+/// Whether only one of the entries has the field, a mismatch under `--strict-fields`.
+fn presence_differs<T>(a: &Option<T>, b: &Option<T>) -> bool {
+    a.is_some() != b.is_some()
+}
+
+/// Tolerance is a fraction of the larger value, 0 requires exact equality.
+fn compare_with_tolerance<T: PartialEq + Sub + Mul<f32> + Copy + PartialOrd>(
+    a: T,
+    b: T,
+    tolerance: f32,
+) -> bool
+where
+    <T as Sub>::Output: PartialOrd<<T as Mul<f32>>::Output>,
+    <T as Mul<f32>>::Output: Debug,
+    <T as Sub>::Output: Debug,
+{
+    let max = if a > b { a } else { b };
+    let min = if a > b { b } else { a };
+    let max_diff = max * tolerance;
+    let diff = max - min;
+    return diff <= max_diff;
+}
+
+/// Lazily compares every destination entry against the sources, yielding a group for
+/// each destination entry that has duplicates so results can be handled as they are found.
+/// Same-named pairs that did not match are collected into `near_misses` when running verbose.
+pub fn find_duplicates<'a>(
+    src_entries: &'a [Entry],
+    dest_entries: &'a [Entry],
+    settings: &'a CompareSettings,
+    near_misses: &'a mut Vec<NearMiss>,
+) -> impl Iterator<Item = DuplicateGroup> + 'a {
+    let clusters = NameClusters::new(src_entries, settings);
+    // Each chunk is compared in parallel but yielded in order, so results are reproducible
+    dest_entries
+        .chunks(COMPARE_CHUNK_SIZE)
+        .flat_map(move |chunk| {
+            chunk
+                .par_iter()
+                .map(|dest_entry| {
+                    match_dest_entry(dest_entry, clusters.candidates(dest_entry), settings)
+                })
+                .collect::<Vec<_>>()
+        })
+        .filter_map(move |(group, found_near_misses)| {
+            near_misses.extend(found_near_misses);
+            group
+        })
+}
+
+/// Sources matching a single destination entry, along with same-named sources that did not
+/// match when running verbose.
+fn match_dest_entry(
+    dest_entry: &Entry,
+    src_entries: &[&Entry],
+    settings: &CompareSettings,
+) -> (Option<DuplicateGroup>, Vec<NearMiss>) {
+    let mut duplicates = vec![];
+    let mut near_misses = vec![];
+    for &src_entry in src_entries {
+        // Overlapping files are reported once before comparing
+        if src_entry.canonical_path == dest_entry.canonical_path {
+            continue;
+        }
+        let rejected_by = rejecting_check(dest_entry, src_entry, settings);
+        if trace::is_enabled() {
+            trace::record(dest_entry, src_entry, rejected_by, settings);
+        }
+        if rejected_by.is_none() {
+            duplicates.push(src_entry.clone());
+        } else if settings.verbose
+            && dest_entry.metadata.file_metadata.base_file_name
+                == src_entry.metadata.file_metadata.base_file_name
+        {
+            near_misses.push(NearMiss {
+                dest_entry: dest_entry.clone(),
+                src_entry: src_entry.clone(),
+                similarity: similarity(dest_entry, src_entry, settings),
+            });
+        }
+    }
+    if duplicates.is_empty() {
+        return (None, near_misses);
+    }
+    let group = DuplicateGroup {
+        keeper: dest_entry.clone(),
+        duplicates,
+    };
+    (Some(group), near_misses)
+}
+
+/// Source entries bucketed by file name. Strict modes only match files with the same name, so
+/// a destination entry only has to be compared with its own bucket. Loose mode compares all.
+struct NameClusters<'a> {
+    all: Vec<&'a Entry>,
+    buckets: Option<HashMap<String, Vec<&'a Entry>>>,
+    ignore_extension: bool,
+    match_remuxes: bool,
+}
+
+impl<'a> NameClusters<'a> {
+    fn new(src_entries: &'a [Entry], settings: &CompareSettings) -> NameClusters<'a> {
+        let mut clusters = NameClusters {
+            all: src_entries.iter().collect(),
+            buckets: None,
+            ignore_extension: settings.ignore_extension,
+            match_remuxes: settings.match_remuxes,
+        };
+        if settings.mode.is_strict() {
+            let mut buckets: HashMap<String, Vec<&Entry>> = HashMap::new();
+            for entry in src_entries {
+                buckets
+                    .entry(clusters.name_key(entry))
+                    .or_default()
+                    .push(entry);
+            }
+            clusters.buckets = Some(buckets);
+        }
+        clusters
+    }
+
+    /// The name that has to be equal for a match without its copy decorations, only the stem
+    /// when extensions may differ.
+    fn name_key(&self, entry: &Entry) -> String {
+        let file_metadata = &entry.metadata.file_metadata;
+        let name = file_metadata.base_file_name.as_str();
+        let stem = undecorated_stem(name);
+        if self.ignore_extension
+            || (self.match_remuxes && is_video_extension(&file_metadata.extension))
+        {
+            return stem.to_string();
+        }
+        match Path::new(name).extension() {
+            Some(extension) => format!("{}.{}", stem, extension.to_string_lossy()),
+            None => stem.to_string(),
+        }
+    }
+
+    fn candidates(&self, dest_entry: &Entry) -> &[&'a Entry] {
+        match &self.buckets {
+            Some(buckets) => buckets
+                .get(&self.name_key(dest_entry))
+                .map(Vec::as_slice)
+                .unwrap_or_default(),
+            None => &self.all,
+        }
+    }
+}
+
+/// Number of pairs skipped by comparing only within name clusters.
+pub fn avoided_comparisons(
+    src_entries: &[Entry],
+    dest_entries: &[Entry],
+    settings: &CompareSettings,
+) -> u64 {
+    let clusters = NameClusters::new(src_entries, settings);
+    dest_entries
+        .iter()
+        .map(|dest_entry| (src_entries.len() - clusters.candidates(dest_entry).len()) as u64)
+        .sum()
+}
+
+/// Fraction of the compared fields that agree, used to rank near misses.
+fn similarity(a: &Entry, b: &Entry, settings: &CompareSettings) -> f32 {
+    let checks = field_checks(a, b, settings);
+    checks.iter().filter(|(_, agreed)| *agreed).count() as f32 / checks.len() as f32
+}
+
+/// Every field both entries have, named and paired with whether it agrees.
+pub fn field_checks(a: &Entry, b: &Entry, settings: &CompareSettings) -> Vec<(&'static str, bool)> {
+    let (a, b) = (&a.metadata, &b.metadata);
+    let mut checks = vec![
+        (
+            "name",
+            undecorated_stem(&a.file_metadata.base_file_name)
+                == undecorated_stem(&b.file_metadata.base_file_name),
+        ),
+        (
+            "extension",
+            a.file_metadata.extension == b.file_metadata.extension,
+        ),
+        (
+            "size",
+            compare_with_tolerance(
+                a.file_metadata.file_size as f32,
+                b.file_metadata.file_size as f32,
+                settings.size_tolerance,
+            ),
+        ),
+    ];
+    if let (Some(a), Some(b)) = (&a.image_metadata, &b.image_metadata) {
+        let brightness = match (
+            a.brightness.and_then(ratio_to_f32),
+            b.brightness.and_then(ratio_to_f32),
+        ) {
+            (Some(a), Some(b)) => Some((a - b).abs() <= BRIGHTNESS_TOLERANCE),
+            (None, None) => None,
+            _ => Some(false),
+        };
+        checks.push(("date", a.date == b.date));
+        checks.extend(
+            [
+                ("sub_sec", compare_if_exist(&a.sub_sec, &b.sub_sec)),
+                ("make", compare_if_exist(&a.make, &b.make)),
+                ("model", compare_if_exist(&a.model, &b.model)),
+                ("lens", compare_if_exist(&a.lens, &b.lens)),
+                ("resolution", compare_if_exist(&a.resolution, &b.resolution)),
+                ("brightness", brightness),
+            ]
+            .into_iter()
+            .filter_map(|(field, agreed)| Some((field, agreed?))),
+        );
+    }
+    if let (Some(a), Some(b)) = (&a.video_metadata, &b.video_metadata) {
+        checks.push(("date", a.date == b.date));
+        checks.extend(
+            compare_if_exist(&a.video_duration, &b.video_duration)
+                .map(|agreed| ("duration", agreed)),
+        );
+    }
+    if let (Some(a), Some(b)) = (&a.file_metadata.digest, &b.file_metadata.digest) {
+        checks.push(("hash", a == b));
+    }
+    if let (Some(a), Some(b)) = (&a.content_hash, &b.content_hash) {
+        checks.push(("content", a == b));
+    }
+    checks
+}
+
+/// Logs the near misses above `NEAR_MISS_THRESHOLD`, the closest ones first.
+pub fn print_near_misses(near_misses: &mut [NearMiss]) {
+    near_misses.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    let close: Vec<&NearMiss> = near_misses
+        .iter()
+        .filter(|near_miss| near_miss.similarity >= NEAR_MISS_THRESHOLD)
+        .collect();
+    if close.is_empty() {
+        return;
+    }
+    log!("\nFiles with the same base name that did not match, by similarity:");
+    for near_miss in close {
+        log!(
+            "{:>4.0}% {}\n      {}",
+            near_miss.similarity * 100.0,
+            near_miss.dest_entry,
+            near_miss.src_entry
+        );
+    }
+}
+
+pub struct NearMiss {
+    pub dest_entry: Entry,
+    pub src_entry: Entry,
+    pub similarity: f32,
+}
+
+/// A destination entry and the source entries that duplicate it.
+pub struct DuplicateGroup {
+    pub keeper: Entry,
+    pub duplicates: Vec<Entry>,
+}
+
+impl DuplicateGroup {
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.duplicates
+            .iter()
+            .map(|entry| entry.metadata.file_metadata.file_size)
+            .sum()
+    }
+
+    /// Number of duplicates at each perceptual distance from the keeper, closest first.
+    /// Duplicates without a perceptual hash, like videos, are left out.
+    pub fn hamming_distances(&self) -> BTreeMap<u32, usize> {
+        let mut distances = BTreeMap::new();
+        let Some(keeper_hash) = self.keeper.metadata.perceptual_hash else {
+            return distances;
+        };
+        for duplicate in &self.duplicates {
+            if let Some(hash) = duplicate.metadata.perceptual_hash {
+                *distances
+                    .entry(hamming_distance(keeper_hash, hash))
+                    .or_default() += 1;
+            }
+        }
+        distances
+    }
+}
+
+/// Number of bits two perceptual hashes differ in.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Lowercases the extensions passed on the command line and drops their leading dots.
+pub fn normalize_extensions(extensions: &[String]) -> Vec<String> {
+    extensions
+        .iter()
+        .map(|extension| extension.trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect()
+}
+
+pub fn is_image_extension(extension: &str) -> bool {
+    IMAGE_EXTENSIONS.contains(&extension)
+        || EXTRA_IMAGE_EXTENSIONS
+            .get()
+            .is_some_and(|extra| extra.iter().any(|allowed| allowed == extension))
+}
+
+pub fn is_video_extension(extension: &str) -> bool {
+    VIDEOS_EXTENSIONS.contains(&extension)
+        || EXTRA_VIDEO_EXTENSIONS
+            .get()
+            .is_some_and(|extra| extra.iter().any(|allowed| allowed == extension))
+}
+
+pub fn is_video(path: &Path) -> bool {
+    let extension = path
+        .extension()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_lowercase();
+    is_video_extension(&extension)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file_named(base_file_name: &str) -> FileMetadata {
+        FileMetadata {
+            base_file_name: base_file_name.to_string(),
+            extension: "jpg".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn decorated_copy_matches_original() {
+        let settings = CompareSettings::new(CompareMode::Paranoid);
+        assert!(FileMetadata::metadata_matches(
+            &file_named("IMG_0001 (1).jpg"),
+            &file_named("IMG_0001.jpg"),
+            &settings
+        ));
+    }
+
+    fn image_with_hash(path: &str, perceptual_hash: u64) -> Entry {
+        Entry {
+            path: PathBuf::from(path),
+            canonical_path: PathBuf::from(path),
+            root: PathBuf::from("/"),
+            metadata: CollectedMetadata {
+                file_metadata: file_named("IMG_0001.jpg"),
+                perceptual_hash: Some(perceptual_hash),
+                ..Default::default()
+            },
+            is_dest: false,
+        }
+    }
+
+    #[test]
+    fn close_perceptual_hashes_match() {
+        let mut settings = CompareSettings::new(CompareMode::Loose);
+        settings.hamming = Some(4);
+        let original = image_with_hash("/a.jpg", 0xF0F0);
+        assert_eq!(
+            rejecting_check(&original, &image_with_hash("/b.jpg", 0xF0F3), &settings),
+            None
+        );
+        // Beyond the threshold the pair falls back to the metadata, which isn't there
+        assert_eq!(
+            rejecting_check(&original, &image_with_hash("/c.jpg", 0x0F0F), &settings),
+            Some("missing_metadata")
+        );
+    }
+
+    #[test]
+    fn group_counts_hamming_distances() {
+        let group = DuplicateGroup {
+            keeper: image_with_hash("/keeper.jpg", 0),
+            duplicates: vec![
+                image_with_hash("/a.jpg", 0),
+                image_with_hash("/b.jpg", 0b101),
+                image_with_hash("/c.jpg", 0b11),
+            ],
+        };
+        assert_eq!(group.hamming_distances(), BTreeMap::from([(0, 1), (2, 2)]));
+    }
+
+    fn entry_with(
+        base_file_name: &str,
+        image_metadata: Option<ImageMetadata>,
+        video_metadata: Option<VideoMetadata>,
+    ) -> Entry {
+        Entry {
+            path: PathBuf::from(base_file_name),
+            canonical_path: PathBuf::from(base_file_name),
+            root: PathBuf::from("/"),
+            metadata: CollectedMetadata {
+                file_metadata: FileMetadata {
+                    extension: Path::new(base_file_name)
+                        .extension()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned(),
+                    ..file_named(base_file_name)
+                },
+                image_metadata,
+                video_metadata,
+                ..Default::default()
+            },
+            is_dest: false,
+        }
+    }
+
+    #[test]
+    fn photo_matches_clip_in_assumed_timezone() {
+        let photo = entry_with(
+            "IMG_0001.jpg",
+            Some(ImageMetadata {
+                date: "2024:05:01 14:30:01".to_string(),
+                ..Default::default()
+            }),
+            None,
+        );
+        // The same moment in UTC, as MP4 stores it
+        let clip = entry_with(
+            "IMG_0001.mov",
+            None,
+            Some(VideoMetadata {
+                date: "2024-05-01T12:30:00+00:00".to_string(),
+                ..Default::default()
+            }),
+        );
+        let mut settings = CompareSettings::new(CompareMode::Paranoid);
+        settings.ignore_extension = true;
+        assert_eq!(
+            rejecting_check(&photo, &clip, &settings),
+            Some("missing_metadata")
+        );
+        settings.assume_tz = FixedOffset::east_opt(2 * 60 * 60);
+        assert_eq!(rejecting_check(&photo, &clip, &settings), None);
+        settings.assume_tz = FixedOffset::east_opt(-5 * 60 * 60);
+        assert_eq!(rejecting_check(&photo, &clip, &settings), Some("date"));
+    }
+
+    #[test]
+    fn numbered_copy_matches_original() {
+        let settings = CompareSettings::new(CompareMode::Paranoid);
+        assert!(FileMetadata::metadata_matches(
+            &file_named("IMG_1234_2.jpg"),
+            &file_named("IMG_1234.jpg"),
+            &settings
+        ));
+    }
+
+    #[test]
+    fn numbered_shots_do_not_match() {
+        // Loose mode doesn't compare names, only the sequence numbers keep the frames apart
+        let settings = CompareSettings::new(CompareMode::Loose);
+        assert!(!FileMetadata::metadata_matches(
+            &file_named("IMG_0001_1.jpg"),
+            &file_named("IMG_0001_2.jpg"),
+            &settings
+        ));
+    }
+}
@@ -1,66 +1,312 @@
+#[macro_use]
+extern crate rcc;
+
 use anyhow::{Error, Result, anyhow, bail};
-use chrono::DateTime;
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, Utc};
 use clap::{Parser, ValueEnum};
+use colored::Colorize;
 use nom_exif::*;
 use num_rational::Ratio;
+use rayon::prelude::*;
 use rexiv2::Metadata;
 use sha2::{Digest, Sha256};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashSet;
 use std::ffi::OsStr;
-use std::fmt::{Debug, Display};
+use std::fmt::Display;
 use std::fs::File;
-use std::io::{BufReader, Read, Write};
-use std::ops::{Mul, Sub};
+use std::io::{BufReader, IsTerminal, Read, Seek, SeekFrom, Write};
 use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{fs, io};
 
+use rcc::hashing::{self, HashAlgorithm};
+use rcc::trace;
+use rcc::*;
+
+use hash_db::HashDb;
+use ignore::IgnoreList;
+use manifest::Manifest;
+use script::{ActionWriter, Invocation, ScriptFormat};
+use stats::Stats;
+
+/// Cleared when gexiv2 fails to initialize, e.g. when the library isn't installed.
+static REXIV2_AVAILABLE: AtomicBool = AtomicBool::new(true);
+
+mod avi;
+mod content;
+mod explain;
+mod hash_db;
+mod ignore;
+mod manifest;
+mod motion;
+mod perceptual;
+mod report;
+mod script;
+mod stats;
+mod watch;
 mod xmp;
 
-const IMAGE_EXTENSIONS: [&str; 3] = ["heic", "jpg", "jpeg"];
-const VIDEOS_EXTENSIONS: [&str; 3] = ["mov", "mp4", "avi"];
+const AAE_EXTENSIONS: [&str; 2] = ["AAE", "aae"];
+const SIDECAR_EXTENSIONS: [&str; 2] = ["xmp", "aae"];
 
-const MP4_TO_UNIX_OFFSET: u64 = 2_082_844_800;
+// Anything smaller can't hold a real photo or video, only a truncated copy
+const MIN_IMAGE_SIZE: u64 = 1024;
+const MIN_VIDEO_SIZE: u64 = 4 * 1024;
 
-type Sha256Bytes = sha2::digest::Output<Sha256>;
+const DEFAULT_OUTPUT: &str = "run.sh";
+const FILE_LIST_EXTENSION: &str = "files0";
 
-#[derive(Clone, Eq, PartialEq, Debug)]
-struct CollectedMetadata {
-    file_metadata: FileMetadata,
-    image_metadata: Option<ImageMetadata>,
-    video_metadata: Option<VideoMetadata>,
-    sha_metadata: Option<Sha256Bytes>,
-}
+const STDIN_PATH: &str = "-";
+const STDOUT_PATH: &str = "-";
+
+// Tried in order, the first one present is used as the capture date
+const EXIF_DATE_TAGS: [(&str, DateSource); 3] = [
+    ("Exif.Photo.DateTimeOriginal", DateSource::Original),
+    ("Exif.Photo.DateTimeDigitized", DateSource::Digitized),
+    ("Exif.Image.DateTime", DateSource::Modified),
+];
+const SINCE_DATE_FORMAT: &str = "%Y-%m-%d";
+const SINCE_DATE_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+const MP4_TO_UNIX_OFFSET: u64 = 2_082_844_800;
 
 #[derive(Parser, Clone)]
 struct Cli {
     #[arg(short = 'v', long, default_value_t = false)]
     verbose: bool,
 
+    #[arg(short = 'q', long, default_value_t = false)]
+    quiet: bool,
+
+    #[arg(long, default_value_t = false)]
+    summary_only: bool,
+
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
     #[arg(short = 'e', long)]
     exclude: Vec<String>,
 
     #[arg(short = 'f', long, default_value_t = false)]
     flip_exclusion: bool,
 
+    #[arg(long, default_value_t = false)]
+    show_included: bool,
+
     #[arg(short = 'a', long, default_value_t = true)]
     include_videos: bool,
 
+    #[arg(long, default_value_t = false)]
+    include_hidden: bool,
+
+    #[arg(long, value_delimiter = ',')]
+    exclude_extension: Vec<String>,
+
+    #[arg(long, value_delimiter = ',')]
+    image_ext: Vec<String>,
+
+    #[arg(long, value_delimiter = ',')]
+    video_ext: Vec<String>,
+
+    #[arg(long, value_parser = parse_since)]
+    since: Option<DateTime<Utc>>,
+
     #[arg(value_enum, default_value_t = CompareMode::Paranoid)]
     mode: CompareMode,
 
-    #[arg(short = 'o', long, default_value = "run.sh")]
-    output: PathBuf,
+    #[arg(long, value_enum, default_value_t = HashAlgorithm::Xxh3)]
+    hash_algorithm: HashAlgorithm,
+
+    #[arg(short = 'o', long)]
+    output: Option<PathBuf>,
 
     #[arg(short = 'c', long)]
     command: Option<FileCommand>,
 
-    #[arg(short, long)]
-    dest: PathBuf,
+    #[arg(long, value_enum, default_value_t = ScriptFormat::Sh)]
+    script_format: ScriptFormat,
+
+    #[arg(long)]
+    move_to: Option<PathBuf>,
+
+    #[arg(long)]
+    copy_to: Option<PathBuf>,
+
+    #[arg(short = 'u', long, default_value_t = false)]
+    undo: bool,
+
+    #[arg(long, default_value_t = false)]
+    annotate: bool,
+
+    #[arg(long, default_value_t = false, conflicts_with = "annotate")]
+    no_comments: bool,
+
+    #[arg(long, default_value_t = false)]
+    verify_bytes: bool,
+
+    #[arg(long, default_value_t = false)]
+    relative_symlinks: bool,
+
+    #[arg(long, default_value_t = false)]
+    fail_on_dupes: bool,
+
+    #[arg(long, default_value_t = false)]
+    include_sidecars: bool,
+
+    #[arg(long, default_value_t = false)]
+    motion_photos: bool,
+
+    #[arg(long, default_value_t = false)]
+    compare_audio: bool,
+
+    #[arg(long, default_value_t = false)]
+    require_gps_match: bool,
+
+    #[arg(long, default_value_t = false)]
+    strict_fields: bool,
+
+    #[arg(long, default_value_t = false)]
+    content_wins: bool,
+
+    #[arg(long)]
+    hamming: Option<u32>,
+
+    #[arg(long)]
+    organize_by: Option<OrganizeBy>,
+
+    #[arg(long)]
+    resolve_conflicts: Option<ConflictResolution>,
+
+    #[arg(long, default_value_t = false, conflicts_with = "organize_by")]
+    keep_structure: bool,
+
+    #[arg(long)]
+    report: Option<PathBuf>,
+
+    #[arg(long)]
+    keepers: Option<PathBuf>,
+
+    #[arg(long)]
+    video_manifest: Option<PathBuf>,
+
+    #[arg(long, default_value_t = false)]
+    stats_by_model: bool,
+
+    #[arg(long, default_value_t = false)]
+    stats_by_dir: bool,
+
+    #[arg(long)]
+    ignore_list: Option<PathBuf>,
+
+    #[arg(long, default_value_t = false)]
+    report_new: bool,
+
+    #[arg(long, default_value_t = false)]
+    allow_mtime_date: bool,
+
+    #[arg(long)]
+    print0: Option<PathBuf>,
+
+    #[arg(long, default_value_t = false)]
+    thumb_hash: bool,
+
+    #[arg(long)]
+    sample_bytes: Option<u64>,
+
+    #[arg(long)]
+    prefer: Vec<PathBuf>,
+
+    #[arg(long, default_value_t = false, conflicts_with_all = ["prefer", "hash_db"])]
+    clean_dest: bool,
+
+    #[arg(long)]
+    keep: Option<KeepPolicy>,
+
+    #[arg(long)]
+    protect: Vec<PathBuf>,
+
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    #[arg(long)]
+    compare_manifest: Option<PathBuf>,
+
+    #[arg(long, default_value_t = false)]
+    ignore_extension: bool,
+
+    #[arg(long, value_delimiter = ',')]
+    copy_suffixes: Option<Vec<String>>,
+
+    #[arg(long, default_value_t = false)]
+    match_remuxes: bool,
+
+    #[arg(long, allow_hyphen_values = true)]
+    assume_tz: Option<FixedOffset>,
+
+    #[arg(long, default_value_t = false)]
+    same_relative_path: bool,
+
+    #[arg(long, value_enum, default_value_t = MissingMetadata::Reject)]
+    missing_metadata: MissingMetadata,
+
+    #[arg(long, default_value_t = false)]
+    watch: bool,
+
+    #[arg(long)]
+    limit: Option<usize>,
+
+    #[arg(long)]
+    max_pairs: Option<u64>,
+
+    #[arg(long, default_value_t = 2)]
+    min_copies: usize,
+
+    #[arg(long, num_args = 0..=1, default_missing_value = "2")]
+    compare_fs_date: Option<u64>,
+
+    #[arg(short = 't', long, default_value_t = 0)]
+    threads: usize,
+
+    #[arg(long, default_value_t = 1.0)]
+    size_tolerance: f32,
+
+    #[arg(long)]
+    resolution_tolerance: Option<f32>,
+
+    #[arg(long)]
+    hash_db: Option<PathBuf>,
+
+    #[arg(long, default_value = "hashes")]
+    hash_db_table: String,
+
+    #[arg(long, default_value = "hash")]
+    hash_db_hash_column: String,
+
+    #[arg(long, default_value = "path")]
+    hash_db_path_column: String,
+
+    #[arg(long)]
+    explain: Option<PathBuf>,
+
+    #[arg(long)]
+    trace: Option<PathBuf>,
+
+    #[arg(short, long, required_unless_present_any = ["hash_db", "explain"])]
+    dest: Vec<PathBuf>,
 
     // #[arg(short, long, value_delimiter = ' ', num_args = 1..)]
     // src: Vec<PathBuf>,
-    #[arg(short, long)]
+    #[arg(
+        short,
+        long,
+        required_unless_present = "explain",
+        default_value = "",
+        hide_default_value = true
+    )]
     src: PathBuf,
 }
 
@@ -70,6 +316,9 @@ enum FileCommand {
     Copy,
     Delete,
     Print,
+    Rsync,
+    Hardlink,
+    Symlink,
 }
 
 impl Display for FileCommand {
@@ -79,22 +328,51 @@ impl Display for FileCommand {
             FileCommand::Copy => f.write_str("copy"),
             FileCommand::Delete => f.write_str("delete"),
             FileCommand::Print => f.write_str("print"),
+            FileCommand::Rsync => f.write_str("rsync"),
+            FileCommand::Hardlink => f.write_str("hardlink"),
+            FileCommand::Symlink => f.write_str("symlink"),
         }
     }
 }
 
 #[derive(PartialEq, Clone, Copy, ValueEnum)]
-enum CompareMode {
-    Loose,
-    Paranoid,
-    Exact,
+enum OutputFormat {
+    Text,
+    Jsonl,
 }
 
-#[derive(Clone, Eq, PartialEq, Debug)]
-struct Entry {
-    path: PathBuf,
-    metadata: CollectedMetadata,
-    is_dest: bool,
+#[derive(PartialEq, Clone, Copy, ValueEnum)]
+enum OrganizeBy {
+    Date,
+}
+
+#[derive(PartialEq, Clone, Copy, ValueEnum)]
+enum KeepPolicy {
+    HighestRes,
+}
+
+#[derive(PartialEq, Clone, Copy, ValueEnum)]
+enum ConflictResolution {
+    Suffix,
+    DirName,
+}
+
+impl FileCommand {
+    fn is_destructive(&self) -> bool {
+        matches!(
+            self,
+            FileCommand::Move
+                | FileCommand::Delete
+                | FileCommand::Rsync
+                | FileCommand::Hardlink
+                | FileCommand::Symlink
+        )
+    }
+
+    /// Replaces the file with a link, so its path and sidecars stay in place.
+    fn is_link(&self) -> bool {
+        matches!(self, FileCommand::Hardlink | FileCommand::Symlink)
+    }
 }
 
 fn compute_file_sha256(path: &Path) -> io::Result<sha2::digest::Output<Sha256>> {
@@ -114,370 +392,1268 @@ fn compute_file_sha256(path: &Path) -> io::Result<sha2::digest::Output<Sha256>>
     Ok(hasher.finalize())
 }
 
-impl Display for Entry {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("dest: {}", self.is_dest))?;
-        f.write_fmt(format_args!(" p: {:?}", self.path))?;
-        f.write_fmt(format_args!(" m: {}", self.metadata))?;
-        Ok(())
+fn files_identical(a: &Path, b: &Path) -> io::Result<bool> {
+    let file_a = File::open(a)?;
+    let file_b = File::open(b)?;
+    if file_a.metadata()?.len() != file_b.metadata()?.len() {
+        return Ok(false);
     }
-}
 
-impl Display for CollectedMetadata {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("f: {}", self.file_metadata))?;
-        if let Some(meta) = self.image_metadata.clone() {
-            f.write_fmt(format_args!(" i: {}", meta))?;
+    let mut reader_a = BufReader::new(file_a);
+    let mut reader_b = BufReader::new(file_b);
+    let mut buffer_a = [0u8; 8192];
+    let mut buffer_b = [0u8; 8192];
+
+    loop {
+        let bytes_read = reader_a.read(&mut buffer_a)?;
+        if bytes_read == 0 {
+            return Ok(true);
         }
-        if let Some(meta) = self.video_metadata.clone() {
-            f.write_fmt(format_args!(" v: {}", meta))?;
+        reader_b.read_exact(&mut buffer_b[..bytes_read])?;
+        if buffer_a[..bytes_read] != buffer_b[..bytes_read] {
+            return Ok(false);
         }
-        Ok(())
     }
 }
 
-impl Display for FileMetadata {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("base: {}", self.base_file_name))?;
-        f.write_fmt(format_args!(" s: {}", self.file_size))?;
-        f.write_fmt(format_args!(" e: {}", self.extension))?;
-        if let Some(date) = self.creation_date.clone() {
-            f.write_fmt(format_args!(" d: {}", date))?;
-        }
-        Ok(())
+/// Fingerprints a file by its size and the first and last `sample_bytes` bytes.
+fn compute_sample_sha256(path: &Path, sample_bytes: u64) -> io::Result<Sha256Bytes> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = Sha256::new();
+    hasher.update(size.to_le_bytes());
+
+    let head = sample_bytes.min(size);
+    let mut buffer = vec![0u8; head as usize];
+    file.read_exact(&mut buffer)?;
+    hasher.update(&buffer);
+
+    // Don't read the bytes already covered by the head twice
+    let tail = sample_bytes.min(size - head);
+    if tail > 0 {
+        file.seek(SeekFrom::End(-(tail as i64)))?;
+        buffer.truncate(tail as usize);
+        file.read_exact(&mut buffer)?;
+        hasher.update(&buffer);
     }
+
+    Ok(hasher.finalize())
 }
 
-impl Display for VideoMetadata {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("date: {}", self.date))?;
-        if let Some(duration) = self.video_duration.clone() {
-            f.write_fmt(format_args!(" d: {:?}", duration))?;
+impl From<&Cli> for CompareSettings {
+    fn from(cli: &Cli) -> Self {
+        CompareSettings {
+            mode: cli.mode,
+            thumb_hash: cli.thumb_hash,
+            size_tolerance: cli.size_tolerance / 100.0,
+            resolution_tolerance: cli.resolution_tolerance.map(|percent| percent / 100.0),
+            ignore_extension: cli.ignore_extension,
+            fs_date_tolerance: cli.compare_fs_date.map(Duration::from_secs),
+            same_relative_path: cli.same_relative_path,
+            missing_metadata: cli.missing_metadata,
+            compare_audio: cli.compare_audio,
+            require_gps_match: cli.require_gps_match,
+            strict_fields: cli.strict_fields,
+            content_wins: cli.content_wins,
+            hamming: cli.hamming,
+            match_remuxes: cli.match_remuxes,
+            assume_tz: cli.assume_tz,
+            verbose: cli.verbose,
         }
-        Ok(())
     }
 }
 
-impl Display for ImageMetadata {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("date: {}", self.date))?;
-        if let Some(model) = self.model.clone() {
-            f.write_fmt(format_args!(" {}", model))?;
-        }
-        if let Some((x, y)) = self.resolution.clone() {
-            f.write_fmt(format_args!(" {}x{}", x, y))?;
-        }
-        if let Some(brightness) = self.brightness.clone() {
-            f.write_fmt(format_args!(" b: {}", brightness))?;
-        }
-        Ok(())
+/// Sub-second tags are decimal fractions, so `5`, `50` and `500 ` are the same value.
+fn normalize_sub_sec(sub_sec: &str) -> Option<String> {
+    let digits = sub_sec.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
     }
+    let trimmed = digits.trim_end_matches('0');
+    Some(if trimmed.is_empty() { "0" } else { trimmed }.to_string())
 }
 
-#[derive(Default, Clone, Eq, PartialEq, Debug)]
-struct ImageMetadata {
-    date: String,
-    resolution: Option<(Ratio<i32>, Ratio<i32>)>,
-    model: Option<String>,
-    brightness: Option<String>,
+/// Logs how far the duplicates of a group are from the keeper, e.g. `0: 2, 5: 1`, for tuning
+/// `--hamming` on real data.
+fn print_hamming_distances(group: &DuplicateGroup) {
+    let distances = group.hamming_distances();
+    if distances.is_empty() {
+        return;
+    }
+    log!(
+        "Hamming distances to {}: {}",
+        group.keeper.path.display(),
+        distances
+            .iter()
+            .map(|(distance, count)| format!("{}: {}", distance, count))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
 }
 
-#[derive(Default, Clone, Eq, PartialEq, Debug)]
-struct FileMetadata {
-    base_file_name: String,
-    file_size: u64,
-    extension: String,
-    creation_date: Option<String>,
+/// Whether the file lives under one of the `--protect` directories and must never be acted on.
+fn is_protected(canonical_path: &Path, protected: &[PathBuf]) -> bool {
+    protected.iter().any(|dir| canonical_path.starts_with(dir))
 }
 
-trait CompareMetadata<T> {
-    fn metadata_matches(a: &T, b: &T, mode: Cli) -> bool;
+/// Orders copies by total pixels for `--keep highest-res`, by file size when either has no
+/// resolution.
+fn compare_quality(a: &Entry, b: &Entry) -> CmpOrdering {
+    let pixels = |entry: &Entry| {
+        entry
+            .metadata
+            .image_metadata
+            .as_ref()
+            .and_then(|image| image.resolution)
+            .map(total_pixels)
+    };
+    match (pixels(a), pixels(b)) {
+        (Some(a_pixels), Some(b_pixels)) => a_pixels.total_cmp(&b_pixels),
+        _ => a
+            .metadata
+            .file_metadata
+            .file_size
+            .cmp(&b.metadata.file_metadata.file_size),
+    }
 }
 
-impl CompareMetadata<sha2::digest::Output<Sha256>> for sha2::digest::Output<Sha256> {
-    fn metadata_matches(
-        a: &sha2::digest::Output<Sha256>,
-        b: &sha2::digest::Output<Sha256>,
-        _mode: Cli,
-    ) -> bool {
-        a == b
-    }
+/// Position of the first `--prefer` prefix containing the path, lower ranks are kept.
+fn preference_rank(canonical_path: &Path, prefer: &[PathBuf]) -> usize {
+    prefer
+        .iter()
+        .position(|prefix| canonical_path.starts_with(prefix))
+        .unwrap_or(prefer.len())
 }
 
-#[derive(Default, Clone, Eq, PartialEq, Debug)]
-struct VideoMetadata {
-    date: String,
-    video_duration: Option<Duration>,
+struct Action {
+    entry: Entry,
+    dest_entry: Entry,
+    action: FileCommand,
+    // Where a move or copy puts the file, assigned when the action is added to the script
+    target: Option<PathBuf>,
 }
 
-impl CompareMetadata<VideoMetadata> for VideoMetadata {
-    fn metadata_matches(a: &VideoMetadata, b: &VideoMetadata, cli: Cli) -> bool {
-        if a.date != b.date {
-            return false;
+/// Writes the script while duplicates are still being found, so an interrupted run leaves the
+/// actions found so far behind. It is only made executable once complete.
+struct ScriptWriter {
+    command: FileCommand,
+    format: Box<dyn ActionWriter>,
+    writer: Box<dyn Write>,
+    file: Option<File>,
+    script_path: PathBuf,
+    // Only written for the rsync command
+    file_list_path: PathBuf,
+    actions: Vec<Action>,
+    // Claimed by earlier moves and copies
+    taken_targets: HashSet<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let cli: Cli = Cli::parse();
+
+    let print0_to_stdout = cli
+        .print0
+        .as_ref()
+        .is_some_and(|p| p.as_os_str() == STDOUT_PATH);
+    let script_to_stdout = cli
+        .output
+        .as_ref()
+        .is_some_and(|p| p.as_os_str() == STDOUT_PATH);
+    let jsonl_to_stdout = cli.format == OutputFormat::Jsonl;
+    let stdout_taken = print0_to_stdout || script_to_stdout || jsonl_to_stdout;
+    if stdout_taken {
+        LOG_TO_STDERR.store(true, Ordering::Relaxed);
+    }
+    let _ = EXTRA_IMAGE_EXTENSIONS.set(normalize_extensions(&cli.image_ext));
+    let _ = EXTRA_VIDEO_EXTENSIONS.set(normalize_extensions(&cli.video_ext));
+    if let Some(suffixes) = &cli.copy_suffixes {
+        let _ = COPY_SUFFIXES.set(
+            suffixes
+                .iter()
+                .filter(|suffix| !suffix.is_empty())
+                .cloned()
+                .collect(),
+        );
+    }
+    let is_terminal = if stdout_taken {
+        io::stderr().is_terminal()
+    } else {
+        io::stdout().is_terminal()
+    };
+    if !is_terminal || std::env::var_os("NO_COLOR").is_some() {
+        colored::control::set_override(false);
+    }
+
+    if cli.command == Some(FileCommand::Move) && cli.move_to.is_none() {
+        log!("{}", "The move command requires --move-to".red());
+        return ExitCode::FAILURE;
+    }
+
+    if cli.command == Some(FileCommand::Copy) && cli.copy_to.is_none() {
+        log!("{}", "The copy command requires --copy-to".red());
+        return ExitCode::FAILURE;
+    }
+
+    if (cli.move_to.is_some() && cli.command != Some(FileCommand::Move))
+        || (cli.copy_to.is_some() && cli.command != Some(FileCommand::Copy))
+    {
+        log!(
+            "{}",
+            "--move-to and --copy-to require the move and copy commands respectively".red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if let Some(target_dir) = cli.move_to.as_ref().or(cli.copy_to.as_ref())
+        && path_exists(target_dir.clone())
+        && !target_dir.is_dir()
+    {
+        log!(
+            "{}",
+            format!("Target {:?} exists and is not a directory", target_dir).red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if cli.hamming.is_some_and(|distance| distance > u64::BITS) {
+        log!(
+            "{}",
+            format!(
+                "--hamming can be at most {}, the bits in a perceptual hash",
+                u64::BITS
+            )
+            .red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if cli.keep_structure && !matches!(cli.command, Some(FileCommand::Move | FileCommand::Copy)) {
+        log!(
+            "{}",
+            "--keep-structure requires the move or copy command".red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if cli.resolve_conflicts.is_some()
+        && !matches!(cli.command, Some(FileCommand::Move | FileCommand::Copy))
+    {
+        log!(
+            "{}",
+            "--resolve-conflicts requires the move or copy command".red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if cli.organize_by.is_some()
+        && !matches!(cli.command, Some(FileCommand::Move | FileCommand::Copy))
+    {
+        log!(
+            "{}",
+            "--organize-by requires the move or copy command".red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if script_to_stdout && (print0_to_stdout || cli.undo) {
+        log!(
+            "{}",
+            "--output - can't be combined with --print0 - or --undo".red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if jsonl_to_stdout && (print0_to_stdout || script_to_stdout) {
+        log!(
+            "{}",
+            "--format jsonl writes to stdout and can't be combined with --print0 - or --output -"
+                .red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if cli.watch && (cli.command.is_some() || cli.src.as_os_str() == STDIN_PATH) {
+        log!(
+            "{}",
+            "--watch only prints duplicates and needs a source directory, it can't be combined with --command or reading from stdin".red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if let Err(err) = rexiv2::initialize() {
+        log!(
+            "{}",
+            format!("Unable to initialize rexiv2, images are read with nom-exif only: {err}")
+                .yellow()
+        );
+        REXIV2_AVAILABLE.store(false, Ordering::Relaxed);
+    }
+
+    if let Some(path) = &cli.explain {
+        if let Err(err) = explain::explain_file(path, &cli) {
+            log!("{}", format!("Unable to read {path:?}: {err}").red());
+            return ExitCode::FAILURE;
         }
+        return ExitCode::SUCCESS;
+    }
 
-        if let Some(duration) = compare_if_exist(&a.video_duration, &b.video_duration) {
-            if !duration {
-                return false;
+    // A mistyped path would otherwise scan nothing and silently report no duplicates
+    let missing: Vec<&PathBuf> = [&cli.src]
+        .into_iter()
+        .chain(&cli.dest)
+        .filter(|path| path.as_os_str() != STDIN_PATH && !path.is_dir() && !path.is_file())
+        .collect();
+    if !missing.is_empty() {
+        for path in missing {
+            log!(
+                "{}",
+                format!("{:?} doesn't exist or isn't a file or directory", path).red()
+            );
+        }
+        return ExitCode::FAILURE;
+    }
+
+    // 0 lets rayon pick the number of threads based on available cores
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.threads)
+        .build_global()
+        .expect("Unable to initialize thread pool");
+
+    let mut stats = Stats::default();
+
+    let scan_start = Instant::now();
+    let src_entries = scan_directories(&vec![cli.src.clone()], false, &cli, &mut stats);
+    // The hash database replaces the destination, there is nothing to scan
+    let dest_entries = if cli.hash_db.is_some() {
+        vec![]
+    } else {
+        scan_directories(&cli.dest, true, &cli, &mut stats)
+    };
+    stats.scan_time = scan_start.elapsed();
+
+    if cli.stats_by_model {
+        log!();
+        stats::print_model_breakdown(
+            src_entries
+                .iter()
+                .chain(dest_entries.iter())
+                .filter_map(|entry| entry.metadata.image_metadata.as_ref())
+                .map(|image| image.model.as_deref()),
+        );
+    }
+
+    if cli.manifest.is_some() || cli.compare_manifest.is_some() {
+        let manifest = Manifest::from_entries(src_entries.iter().chain(dest_entries.iter()));
+        if let Some(old_path) = &cli.compare_manifest {
+            let old_manifest = Manifest::load(old_path).expect("Failed to read manifest");
+            log!();
+            manifest::print_diff(&old_manifest, &manifest);
+        }
+        if let Some(manifest_path) = &cli.manifest {
+            manifest
+                .write(manifest_path)
+                .expect("Failed to write manifest");
+            log!("Manifest written to {:?}", manifest_path);
+        }
+    }
+
+    log!("\nSearching for duplicates\n");
+
+    let ignore_list = match &cli.ignore_list {
+        Some(path) => IgnoreList::load(path).expect("Failed to read ignore list"),
+        None => IgnoreList::default(),
+    };
+
+    let src_paths: HashSet<&PathBuf> = src_entries
+        .iter()
+        .map(|entry| &entry.canonical_path)
+        .collect();
+    let overlapping: HashSet<&PathBuf> = dest_entries
+        .iter()
+        .map(|entry| &entry.canonical_path)
+        .filter(|path| src_paths.contains(path))
+        .collect();
+    stats.overlapping_files = overlapping.len();
+    if !overlapping.is_empty() {
+        log!(
+            "{}",
+            format!(
+                "{} files are both in source and destination directories, they are not compared with themselves",
+                overlapping.len()
+            )
+            .yellow()
+        );
+        if cli.verbose {
+            for path in &overlapping {
+                log!("  {}", path.display());
             }
-        } else {
-            if cli.mode == CompareMode::Paranoid {
-                return false;
+        }
+    }
+
+    let compare_start = Instant::now();
+    let settings = CompareSettings::from(&cli);
+    stats.comparisons_avoided = avoided_comparisons(&src_entries, &dest_entries, &settings);
+    let estimated_pairs = (src_entries.len() as u64 * dest_entries.len() as u64)
+        .saturating_sub(stats.comparisons_avoided);
+    if !cli.quiet {
+        log!("Comparing {} pairs", estimated_pairs);
+    }
+    // Checked before comparing, a runaway scan over overlapping trees can take hours
+    if let Some(max_pairs) = cli.max_pairs
+        && estimated_pairs > max_pairs
+    {
+        log!(
+            "{}",
+            format!(
+                "{} pairs would be compared, more than --max-pairs {}. Narrow down --src and --dest or use a strict --mode",
+                estimated_pairs, max_pairs
+            )
+            .red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    if let Some(trace_path) = &cli.trace
+        && let Err(err) = trace::open(trace_path)
+    {
+        log!(
+            "{}",
+            format!("Failed to open trace {trace_path:?}: {}", err).red()
+        );
+        return ExitCode::FAILURE;
+    }
+
+    let mut script = cli
+        .command
+        .map(|command| ScriptWriter::create(&cli, command, script_to_stdout));
+    let mut saved_space = 0u64;
+    let mut counted_paths = HashSet::new();
+    let mut victims = vec![];
+    let mut matched_sources = HashSet::new();
+
+    let mut groups = vec![];
+
+    let prefer: Vec<PathBuf> = cli
+        .prefer
+        .iter()
+        .map(|prefix| prefix.canonicalize().unwrap_or(prefix.clone()))
+        .collect();
+    let protected: Vec<PathBuf> = cli
+        .protect
+        .iter()
+        .map(|dir| dir.canonicalize().unwrap_or(dir.clone()))
+        .collect();
+
+    let mut near_misses = vec![];
+    for found in find_duplicates(&src_entries, &dest_entries, &settings, &mut near_misses) {
+        if found.duplicates.len() + 1 < cli.min_copies {
+            continue;
+        }
+        let dest_entry = &found.keeper;
+        let mut keeper_entry = None;
+        let mut duplicates: Vec<Entry> = vec![];
+        for src_entry in &found.duplicates {
+            // Checked before anything is recorded, so a pair that differs doesn't end up in
+            // the groups, reports or keepers either
+            if cli.verify_bytes && cli.command.is_some_and(|c| c.is_destructive()) {
+                match files_identical(&src_entry.path, &dest_entry.path) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        log!(
+                            "{}",
+                            format!(
+                                "Contents differ, not a duplicate: {}: {}",
+                                dest_entry.path.display(),
+                                src_entry.path.display()
+                            )
+                            .yellow()
+                        );
+                        continue;
+                    }
+                    Err(err) => {
+                        log!(
+                            "{}",
+                            format!(
+                                "Unable to compare contents, not a duplicate: {}: {}: {}",
+                                dest_entry.path.display(),
+                                src_entry.path.display(),
+                                err
+                            )
+                            .red()
+                        );
+                        continue;
+                    }
+                }
+            }
+            if !cli.summary_only {
+                log!(
+                    "{}",
+                    format!(
+                        "Duplicate found for: {}: {}",
+                        dest_entry.path.display(),
+                        src_entry.path.display()
+                    )
+                    .green()
+                );
+                if cli.dest.len() > 1 {
+                    log!("Found in destination: {}", dest_entry.root.display());
+                }
+            }
+            let src_protected = is_protected(&src_entry.canonical_path, &protected);
+            let dest_protected = is_protected(&dest_entry.canonical_path, &protected);
+            if src_protected && dest_protected {
+                log!(
+                    "{}",
+                    format!(
+                        "Both copies are protected, not acting on: {}: {}",
+                        dest_entry.path.display(),
+                        src_entry.path.display()
+                    )
+                    .yellow()
+                );
+                matched_sources.insert(src_entry.path.clone());
+                continue;
+            }
+            // A protected copy is always kept, then the better copy with --keep. Otherwise the
+            // destination copy is kept unless the source is trusted with --clean-dest or
+            // --prefer ranks it higher
+            let quality = match cli.keep {
+                Some(KeepPolicy::HighestRes) => compare_quality(src_entry, dest_entry),
+                None => CmpOrdering::Equal,
+            };
+            let keep_source = src_protected
+                || (!dest_protected
+                    && match quality {
+                        CmpOrdering::Equal => {
+                            cli.clean_dest
+                                || preference_rank(&src_entry.canonical_path, &prefer)
+                                    < preference_rank(&dest_entry.canonical_path, &prefer)
+                        }
+                        quality => quality == CmpOrdering::Greater,
+                    });
+            let (keeper, victim) = if keep_source {
+                if !cli.summary_only && src_protected {
+                    log!("Keeping protected: {}", src_entry.path.display());
+                } else if !cli.summary_only && quality == CmpOrdering::Greater {
+                    log!("Keeping higher quality: {}", src_entry.path.display());
+                } else if !cli.summary_only && !cli.clean_dest {
+                    log!("Keeping preferred: {}", src_entry.path.display());
+                }
+                keeper_entry = Some(src_entry.clone());
+                (src_entry, dest_entry)
+            } else {
+                (dest_entry, src_entry)
+            };
+            if !duplicates.iter().any(|entry| entry.path == victim.path) {
+                duplicates.push(victim.clone());
+            }
+            if jsonl_to_stdout {
+                report::write_jsonl_match(&mut io::stdout().lock(), keeper, victim, cli.mode)
+                    .expect("Failed to write match");
+            }
+            matched_sources.insert(src_entry.path.clone());
+            if !cli.summary_only
+                && dest_entry.metadata.file_metadata.base_file_name
+                    != src_entry.metadata.file_metadata.base_file_name
+            {
+                log!("{}", "Files have different names".yellow());
+            }
+
+            if ignore_list.contains(&victim.path) {
+                log!(
+                    "{}",
+                    format!("Ignored, not acting on: {}", victim.path.display()).yellow()
+                );
+                continue;
+            }
+
+            if cli.command == Some(FileCommand::Hardlink)
+                && !can_hardlink(&victim.path, &keeper.path)
+            {
+                continue;
+            }
+
+            // A file can match several other copies, act on it only once
+            if !counted_paths.insert(victim.path.clone()) {
+                continue;
+            }
+            saved_space += victim.metadata.file_metadata.file_size;
+            victims.push(victim.path.clone());
+            if let Some(script) = &mut script {
+                script.push(victim.clone(), keeper.clone(), &cli);
+            }
+        }
+        if !duplicates.is_empty() {
+            stats.duplicate_groups += 1;
+            let group = DuplicateGroup {
+                keeper: keeper_entry.unwrap_or(dest_entry.clone()),
+                duplicates,
+            };
+            if cli.hamming.is_some() && !cli.summary_only {
+                print_hamming_distances(&group);
+            }
+            groups.push(group);
+            if cli.limit.is_some_and(|limit| groups.len() >= limit) {
+                stats.limit_reached = true;
+                break;
+            }
+        }
+    }
+    // The database only knows of a single archived copy
+    if let Some(db_path) = &cli.hash_db
+        && cli.min_copies <= 2
+    {
+        let hash_db = HashDb::open(
+            db_path,
+            &cli.hash_db_table,
+            &cli.hash_db_hash_column,
+            &cli.hash_db_path_column,
+        )
+        .expect("Failed to open hash database");
+        for src_entry in &src_entries {
+            if cli.limit.is_some_and(|limit| groups.len() >= limit) {
+                stats.limit_reached = true;
+                break;
+            }
+            // The database stores SHA-256, other digests have to be recomputed
+            let hash = match &src_entry.metadata.file_metadata.digest {
+                Some(digest) if digest.algorithm == HashAlgorithm::Sha256 => {
+                    hex_digest(&digest.bytes)
+                }
+                _ => match compute_file_sha256(&src_entry.path) {
+                    Ok(hash) => hex_digest(&hash),
+                    Err(err) => {
+                        log!(
+                            "{}",
+                            format!("Unable to hash {}: {}", src_entry.path.display(), err)
+                                .yellow()
+                        );
+                        continue;
+                    }
+                },
+            };
+            let Some(archived_path) = hash_db
+                .lookup(&hash)
+                .expect("Failed to query hash database")
+            else {
+                continue;
+            };
+            if !cli.summary_only {
+                log!(
+                    "{}",
+                    format!(
+                        "Duplicate found in hash database: {}: {}",
+                        archived_path,
+                        src_entry.path.display()
+                    )
+                    .green()
+                );
+            }
+            matched_sources.insert(src_entry.path.clone());
+            let keeper = Entry::new(
+                PathBuf::from(archived_path),
+                db_path.clone(),
+                CollectedMetadata::default(),
+                true,
+            );
+            if jsonl_to_stdout {
+                report::write_jsonl_match(&mut io::stdout().lock(), &keeper, src_entry, cli.mode)
+                    .expect("Failed to write match");
+            }
+            stats.duplicate_groups += 1;
+            groups.push(DuplicateGroup {
+                keeper: keeper.clone(),
+                duplicates: vec![src_entry.clone()],
+            });
+
+            if ignore_list.contains(&src_entry.path) {
+                log!(
+                    "{}",
+                    format!("Ignored, not acting on: {}", src_entry.path.display()).yellow()
+                );
+                continue;
+            }
+            if is_protected(&src_entry.canonical_path, &protected) {
+                log!(
+                    "{}",
+                    format!("Protected, not acting on: {}", src_entry.path.display()).yellow()
+                );
+                continue;
+            }
+            if cli.command == Some(FileCommand::Hardlink)
+                && !can_hardlink(&src_entry.path, &keeper.path)
+            {
+                continue;
+            }
+            if counted_paths.insert(src_entry.path.clone()) {
+                saved_space += src_entry.metadata.file_metadata.file_size;
+                victims.push(src_entry.path.clone());
+            }
+            if let Some(script) = &mut script {
+                script.push(src_entry.clone(), keeper, &cli);
+            }
+        }
+    }
+    stats.compare_time = compare_start.elapsed();
+    trace::flush().expect("Failed to write trace");
+
+    if let Some(script) = &mut script
+        && script.command.is_destructive()
+        && !script.command.is_link()
+    {
+        let mut handled: HashSet<PathBuf> = script
+            .actions
+            .iter()
+            .map(|a| a.entry.path.clone())
+            .collect();
+        let mut sidecar_actions = vec![];
+        for action in &script.actions {
+            for sidecar in orphaned_sidecars(&action.entry.path, &handled) {
+                if !handled.insert(sidecar.clone()) {
+                    continue;
+                }
+                if !cli.include_sidecars {
+                    log!(
+                        "{}",
+                        format!("Sidecar will be orphaned: {}", sidecar.display()).yellow()
+                    );
+                    continue;
+                }
+                let entry = sidecar_entry(sidecar, &action.entry);
+                if counted_paths.insert(entry.path.clone()) {
+                    saved_space += entry.metadata.file_metadata.file_size;
+                    victims.push(entry.path.clone());
+                }
+                sidecar_actions.push((entry, action.dest_entry.clone()));
             }
         }
+        for (entry, dest_entry) in sidecar_actions {
+            script.push(entry, dest_entry, &cli);
+        }
+    }
+
+    if let Some(report_path) = &cli.report {
+        report::write_html_report(report_path, &groups).expect("Failed to write report");
+        log!("Report written to {:?}", report_path);
+    }
+
+    if let Some(keepers_path) = &cli.keepers {
+        write_keepers(keepers_path, &groups).expect("Failed to write keepers");
+        log!("Keepers written to {:?}", keepers_path);
+    }
+
+    if let Some(manifest_path) = &cli.video_manifest {
+        let entries = dest_entries.iter().chain(&src_entries);
+        write_video_manifest(manifest_path, entries, &counted_paths, cli.assume_tz)
+            .expect("Failed to write video manifest");
+        log!("Video manifest written to {:?}", manifest_path);
+    }
+
+    if !groups.is_empty() && !cli.summary_only {
+        log!();
+        stats::print_group_table(&groups);
+    }
+
+    if let Some(print0_path) = &cli.print0 {
+        let victims: Vec<&Path> = victims.iter().map(PathBuf::as_path).collect();
+        if print0_to_stdout {
+            write_null_delimited(&mut io::stdout().lock(), &victims)
+        } else {
+            File::create(print0_path)
+                .and_then(|file| write_null_delimited(&mut io::BufWriter::new(file), &victims))
+        }
+        .expect("Failed to write path list");
+    }
+
+    print_near_misses(&mut near_misses);
+
+    if cli.report_new {
+        let new_entries: Vec<&Entry> = src_entries
+            .iter()
+            .filter(|entry| !matched_sources.contains(&entry.path))
+            .collect();
+        log!("\nFiles not found in destination: {}", new_entries.len());
+        for entry in new_entries {
+            log!("  {}", entry.path.display().to_string().cyan());
+        }
+    }
+
+    if cli.stats_by_dir && !victims.is_empty() {
+        let roots: Vec<&Path> = cli
+            .dest
+            .iter()
+            .chain([&cli.src])
+            .map(PathBuf::as_path)
+            .collect();
+        log!();
+        stats::print_directory_breakdown(victims.iter().map(PathBuf::as_path), &roots);
+    }
+
+    let size_str = stats::format_size(saved_space);
+    log!("Total saved space: {}", size_str.as_str().green().bold());
+    log!("\n{}", stats);
+
+    if cli.watch {
+        watch::watch_source(&cli, &dest_entries, &settings).expect("Failed to watch source");
+    }
+
+    let Some(script) = script else {
+        // Without a command there is nothing to script, the output only records the findings
+        if let Some(output) = &cli.output {
+            write_json_report(output, &groups, cli.mode);
+        }
+        return exit_code(&cli, &stats);
+    };
+    script.finish(&cli, &size_str);
 
-        true
+    exit_code(&cli, &stats)
+}
+
+fn exit_code(cli: &Cli, stats: &Stats) -> ExitCode {
+    if cli.fail_on_dupes && stats.duplicate_groups > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
     }
 }
 
-impl CompareMetadata<ImageMetadata> for ImageMetadata {
-    fn metadata_matches(a: &ImageMetadata, b: &ImageMetadata, _cli: Cli) -> bool {
-        if a.date != b.date {
-            return false;
+fn undo_script_path(output: &Path) -> PathBuf {
+    let stem = output.file_stem().unwrap_or(OsStr::new("run"));
+    let mut file_name = stem.to_os_string();
+    file_name.push(".undo");
+    if let Some(extension) = output.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    output.with_file_name(file_name)
+}
+
+fn script_path(cli: &Cli) -> PathBuf {
+    cli.output
+        .clone()
+        .unwrap_or(PathBuf::from(DEFAULT_OUTPUT).with_extension(cli.script_format.extension()))
+}
+
+fn write_json_report(output: &Path, groups: &[DuplicateGroup], mode: CompareMode) {
+    if output.as_os_str() == STDOUT_PATH {
+        report::write_json_report(&mut io::stdout().lock(), groups, mode)
+            .expect("Failed to write report");
+    } else {
+        File::create(output)
+            .map_err(Error::from)
+            .and_then(|file| report::write_json_report(&mut io::BufWriter::new(file), groups, mode))
+            .expect("Failed to write report");
+        log!("Report written to {:?}", output);
+    }
+}
+
+impl ScriptWriter {
+    /// Creates the script and writes its header, the totals follow once the run is complete.
+    fn create(cli: &Cli, command: FileCommand, script_to_stdout: bool) -> ScriptWriter {
+        let (writer, file): (Box<dyn Write>, _) = if script_to_stdout {
+            (Box::new(io::stdout()), None)
+        } else {
+            let file = File::create(script_path(cli)).unwrap();
+            let handle = file.try_clone().unwrap();
+            (Box::new(io::BufWriter::new(file)), Some(handle))
+        };
+        let file_list_path = if script_to_stdout {
+            PathBuf::from(DEFAULT_OUTPUT).with_extension(FILE_LIST_EXTENSION)
+        } else {
+            script_path(cli).with_extension(FILE_LIST_EXTENSION)
+        };
+        let mut script = ScriptWriter {
+            command,
+            format: cli.script_format.writer(),
+            writer,
+            file,
+            script_path: script_path(cli),
+            file_list_path,
+            actions: vec![],
+            taken_targets: HashSet::new(),
+        };
+        let invocation = Invocation {
+            cli,
+            command,
+            script_path: &script.script_path,
+            file_list_path: &script.file_list_path,
+        };
+        script
+            .format
+            .write_header(&mut script.writer, &invocation)
+            .unwrap();
+        script.writer.flush().unwrap();
+        script
+    }
+
+    /// Appends an action on `entry` and flushes it, so it survives if the run is interrupted.
+    fn push(&mut self, entry: Entry, dest_entry: Entry, cli: &Cli) {
+        let mut action = Action {
+            entry,
+            dest_entry,
+            action: self.command,
+            target: None,
+        };
+        if let Some(target_dir) = cli.move_to.as_ref().or(cli.copy_to.as_ref()) {
+            let Some(target) = self.assign_target(&action.entry, target_dir, cli) else {
+                return;
+            };
+            action.target = Some(target);
         }
+        let invocation = Invocation {
+            cli,
+            command: self.command,
+            script_path: &self.script_path,
+            file_list_path: &self.file_list_path,
+        };
+        self.format
+            .write_action(&mut self.writer, &action, &invocation)
+            .unwrap();
+        self.writer.flush().unwrap();
+        self.actions.push(action);
+    }
 
-        if let Some(model) = compare_if_exist(&a.model, &b.model) {
-            if !model {
-                return false;
+    /// Picks where a move or copy puts its file. Without `--resolve-conflicts` actions whose
+    /// target already exists, or was picked by an earlier action, are dropped.
+    fn assign_target(&mut self, entry: &Entry, target_dir: &Path, cli: &Cli) -> Option<PathBuf> {
+        let file_name = entry.path.file_name().unwrap_or_default();
+        let target = target_dir.join(target_subdir(entry, cli)).join(file_name);
+        let target = match cli.resolve_conflicts {
+            Some(resolution) => {
+                resolve_conflict(&target, &entry.path, resolution, &self.taken_targets)
             }
-        }
+            None if self.taken_targets.contains(&target) || path_exists(target.clone()) => {
+                log!(
+                    "{}",
+                    format!(
+                        "Target already exists, skipping: {}: {}",
+                        entry.path.display(),
+                        target.display()
+                    )
+                    .yellow()
+                );
+                return None;
+            }
+            None => target,
+        };
+        self.taken_targets.insert(target.clone());
+        Some(target)
+    }
 
-        if let Some(brightness) = compare_if_exist(&a.brightness, &b.brightness) {
-            if !brightness {
-                return false;
-            }
+    /// Writes the totals and the steps that need every action, then makes the script executable
+    /// and writes the undo script.
+    fn finish(self, cli: &Cli, saved_space: &str) {
+        let ScriptWriter {
+            command,
+            format,
+            mut writer,
+            file,
+            script_path,
+            file_list_path,
+            actions,
+            ..
+        } = self;
+        if command == FileCommand::Rsync {
+            let victims: Vec<&Path> = actions.iter().map(|a| a.entry.path.as_path()).collect();
+            File::create(&file_list_path)
+                .and_then(|file| write_null_delimited(&mut io::BufWriter::new(file), &victims))
+                .expect("Failed to write file list");
         }
-        if let Some(resolution) = compare_if_exist(&a.resolution, &b.resolution) {
-            if !resolution {
-                return false;
-            }
+        let file_list_path = fs::canonicalize(&file_list_path).unwrap_or(file_list_path);
+        let invocation = Invocation {
+            cli,
+            command,
+            script_path: &script_path,
+            file_list_path: &file_list_path,
+        };
+        format
+            .write_footer(&mut writer, &actions, saved_space, &invocation)
+            .unwrap();
+        writer.flush().unwrap();
+        if let Some(file) = &file
+            && format.is_executable()
+        {
+            make_executable(file);
+        }
+        if cli.undo {
+            write_undo_script(format.as_ref(), &actions, &invocation);
         }
-        true
     }
 }
 
-impl CompareMetadata<FileMetadata> for FileMetadata {
-    fn metadata_matches(a: &FileMetadata, b: &FileMetadata, cli: Cli) -> bool {
-        if cli.mode == CompareMode::Paranoid && a.base_file_name != b.base_file_name {
-            return false;
-        }
+fn write_undo_script(format: &dyn ActionWriter, actions: &[Action], invocation: &Invocation) {
+    let undo_path = undo_script_path(invocation.script_path);
+    let undo_file = File::create(&undo_path).unwrap();
+    let mut writer = io::BufWriter::new(&undo_file);
+    format.write_undo(&mut writer, actions, invocation).unwrap();
+    writer.flush().unwrap();
+    drop(writer);
+    if format.is_executable() {
+        make_executable(&undo_file);
+    }
+    log!("Undo script written to {:?}", undo_path);
+}
 
-        if !compare_with_tolerance(a.file_size as f32, b.file_size as f32) {
-            // println!("mismatch on size");
-            return false;
-        }
+const UNDATED_DIR: &str = "undated";
 
-        if a.extension != b.extension {
-            // println!("mismatch on extension");
-            return false;
-        }
+/// Returns the `YYYY/MM` subdirectory for an entry based on its image or video date.
+fn date_bucket(metadata: &CollectedMetadata) -> PathBuf {
+    let date = if let Some(image) = &metadata.image_metadata {
+        Some(image.date.as_str())
+    } else {
+        metadata.video_metadata.as_ref().map(|v| v.date.as_str())
+    };
 
-        true
+    // EXIF dates use `YYYY:MM:DD`, video dates use `YYYY-MM-DD`
+    let Some((year, month)) = date.and_then(|d| Some((d.get(0..4)?, d.get(5..7)?))) else {
+        return PathBuf::from(UNDATED_DIR);
+    };
+    let is_numeric = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if !is_numeric(year) || !is_numeric(month) || year == "0000" {
+        return PathBuf::from(UNDATED_DIR);
     }
+
+    PathBuf::from(year).join(month)
 }
 
-fn entries_match(a: &Entry, b: &Entry, mode: Cli) -> bool {
-    if mode.mode == CompareMode::Exact {
-        if let (Some(a), Some(b)) = (&a.metadata.sha_metadata, &b.metadata.sha_metadata) {
-            return Sha256Bytes::metadata_matches(a, b, mode.clone());
-        } else {
-            return false;
-        }
-    }
-    if !FileMetadata::metadata_matches(
-        &a.metadata.file_metadata,
-        &b.metadata.file_metadata,
-        mode.clone(),
-    ) {
+/// Hardlinks can't span filesystems, and files that are already linked need no action.
+fn can_hardlink(victim: &Path, keeper: &Path) -> bool {
+    let (Ok(victim_metadata), Ok(keeper_metadata)) = (fs::metadata(victim), fs::metadata(keeper))
+    else {
+        log!(
+            "{}",
+            format!(
+                "Unable to read files, skipping hardlink for: {}",
+                victim.display()
+            )
+            .yellow()
+        );
+        return false;
+    };
+    if victim_metadata.dev() != keeper_metadata.dev() {
+        log!(
+            "{}",
+            format!(
+                "Files are on different filesystems, skipping hardlink for: {}",
+                victim.display()
+            )
+            .yellow()
+        );
         return false;
     }
-    let mut metadata_checked = false;
-    if let (Some(a), Some(b)) = (&a.metadata.image_metadata, &b.metadata.image_metadata) {
-        if !ImageMetadata::metadata_matches(a, b, mode.clone()) {
-            return false;
-        }
-        metadata_checked = true;
-    }
-    if let (Some(a), Some(b)) = (&a.metadata.video_metadata, &b.metadata.video_metadata) {
-        if !VideoMetadata::metadata_matches(a, b, mode.clone()) {
-            return false;
-        }
-        metadata_checked = true;
-    }
-
-    return metadata_checked;
+    victim_metadata.ino() != keeper_metadata.ino()
 }
 
-fn compare_if_exist<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> Option<bool> {
-    match (a, b) {
-        (Some(a_val), Some(b_val)) => return Some(a_val == b_val),
-        (None, None) => None,
-        (None, Some(_)) => Some(false),
-        (Some(_), None) => Some(false),
+/// Absolute path of the kept file, or relative to the directory of the replaced one.
+fn symlink_target(action: &Action, relative: bool) -> PathBuf {
+    let keeper = &action.dest_entry.canonical_path;
+    let link_dir = action
+        .entry
+        .canonical_path
+        .parent()
+        .unwrap_or(Path::new("/"));
+    if !relative {
+        return keeper.clone();
     }
-}
 
-const TOLERANCE: f32 = 0.01;
-fn compare_with_tolerance<T: PartialEq + Sub + Mul<f32> + Copy + PartialOrd>(a: T, b: T) -> bool
-where
-    <T as Sub>::Output: PartialOrd<<T as Mul<f32>>::Output>,
-    <T as Mul<f32>>::Output: Debug,
-    <T as Sub>::Output: Debug,
-{
-    let max = if a > b { a } else { b };
-    let min = if a > b { b } else { a };
-    let max_diff = max * TOLERANCE;
-    let diff = max - min;
-    return diff < max_diff;
+    let common = keeper
+        .components()
+        .zip(link_dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut target = PathBuf::new();
+    for _ in link_dir.components().skip(common) {
+        target.push("..");
+    }
+    target.extend(keeper.components().skip(common));
+    target
 }
 
-struct Action {
-    entry: Entry,
-    dest_entry: Entry,
-    action: FileCommand,
+/// Subdirectory of the move or copy target an entry is placed in, flat unless
+/// `--keep-structure` or `--organize-by` is given.
+fn target_subdir(entry: &Entry, cli: &Cli) -> PathBuf {
+    if cli.keep_structure {
+        return relative_path(entry)
+            .and_then(Path::parent)
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+    }
+    match cli.organize_by {
+        Some(OrganizeBy::Date) => date_bucket(&entry.metadata),
+        None => PathBuf::new(),
+    }
 }
 
-fn main() {
-    let cli: Cli = Cli::parse();
-
-    rexiv2::initialize().expect("Unable to initialize rexiv2");
-
-    let src_entries = scan_directories(&vec![cli.src.clone()], false, &cli);
-    let dest_entries = scan_directories(&vec![cli.dest.clone()], true, &cli);
-
-    println!("\nSearching for duplicates\n");
-
-    let mut saved_space = 0u64;
-
-    let mut actions = vec![];
+/// Picks a free path for a move or copy when `target` already exists or was claimed by an
+/// earlier action, e.g. `IMG_1234_1.JPG` or `IMG_1234_Camera.JPG` for a file from `Camera/`.
+fn resolve_conflict(
+    target: &Path,
+    source: &Path,
+    resolution: ConflictResolution,
+    taken: &HashSet<PathBuf>,
+) -> PathBuf {
+    let is_free = |path: &Path| !taken.contains(path) && !path_exists(path.to_path_buf());
+    if is_free(target) {
+        return target.to_path_buf();
+    }
 
-    for dest_entry in dest_entries {
-        for src_entry in &src_entries {
-            if *src_entry.path == dest_entry.path {
-                println!(
-                    "File is both in source and destination directories: {:?}",
-                    dest_entry.path
-                );
-                continue;
-            }
-            if entries_match(&dest_entry, &src_entry, cli.clone()) {
-                println!(
-                    "Duplicate found for: {}: {}",
-                    dest_entry.path.display(),
-                    src_entry.path.display()
-                );
-                if dest_entry.metadata.file_metadata.base_file_name
-                    != src_entry.metadata.file_metadata.base_file_name
-                {
-                    println!("Files have different names");
-                }
+    let stem = target.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = target
+        .extension()
+        .map(|ext| format!(".{}", ext.to_string_lossy()))
+        .unwrap_or_default();
+    let with_suffix = |suffix: &str| target.with_file_name(format!("{stem}_{suffix}{extension}"));
 
-                saved_space += src_entry.metadata.file_metadata.file_size;
-                if let Some(command) = cli.command {
-                    actions.push(Action {
-                        entry: src_entry.clone(),
-                        dest_entry: dest_entry.clone(),
-                        action: command,
-                    });
-                }
-            } else if cli.verbose
-                && dest_entry.metadata.file_metadata.base_file_name
-                    == src_entry.metadata.file_metadata.base_file_name
-            {
-                println!(
-                    "\nFiles have the same base name but did not match: \n{:?}\n{:?}",
-                    dest_entry, src_entry
-                );
-            }
+    if resolution == ConflictResolution::DirName
+        && let Some(dir_name) = source.parent().and_then(Path::file_name)
+    {
+        let candidate = with_suffix(&dir_name.to_string_lossy());
+        if is_free(&candidate) {
+            return candidate;
         }
     }
+    (1..)
+        .map(|n: u32| with_suffix(&n.to_string()))
+        .find(|candidate| is_free(candidate))
+        .unwrap()
+}
 
-    let saved_mb = saved_space / (1024 * 1024);
-    let size_str = if saved_mb >= 1024 {
-        format!("{}.{}GB", saved_mb / 1024, saved_mb % 1024)
-    } else {
-        format!("{}MB", saved_mb)
-    };
-    println!("Total saved space: {}", size_str);
+/// Writes the kept file of every group once per line, in the order the groups were found.
+fn write_keepers(path: &Path, groups: &[DuplicateGroup]) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(File::create(path)?);
+    let mut written = HashSet::new();
+    for group in groups {
+        if written.insert(&group.keeper.path) {
+            writeln!(writer, "{}", group.keeper.path.display())?;
+        }
+    }
+    writer.flush()
+}
 
-    let Some(command) = cli.command else {
-        return;
-    };
+/// Lists every video left after deduplication as `path<TAB>seconds<TAB>date`, oldest first.
+fn write_video_manifest<'a>(
+    path: &Path,
+    entries: impl Iterator<Item = &'a Entry>,
+    victims: &HashSet<PathBuf>,
+    local_offset: Option<FixedOffset>,
+) -> io::Result<()> {
+    // Overlapping source and destination entries are the same file
+    let mut seen = HashSet::new();
+    let mut videos: Vec<(&Entry, &VideoMetadata, Option<DateTime<Utc>>)> = entries
+        .filter(|entry| !victims.contains(&entry.path))
+        .filter(|entry| seen.insert(&entry.canonical_path))
+        .filter_map(|entry| {
+            let video = entry.metadata.video_metadata.as_ref()?;
+            Some((entry, video, parse_video_date(&video.date, local_offset)))
+        })
+        .collect();
+    videos.sort_by(|(a, _, a_date), (b, _, b_date)| {
+        a_date.cmp(b_date).then_with(|| a.path.cmp(&b.path))
+    });
+
+    let mut writer = io::BufWriter::new(File::create(path)?);
+    for (entry, video, date) in videos {
+        let duration = video
+            .video_duration
+            .map(|duration| format!("{:.3}", duration.as_secs_f64()))
+            .unwrap_or_default();
+        let date = date.map_or(video.date.clone(), |date| date.to_rfc3339());
+        writeln!(writer, "{}\t{}\t{}", entry.path.display(), duration, date)?;
+    }
+    writer.flush()
+}
 
-    let mut execution_file = File::create(cli.output.clone()).unwrap();
-    execution_file
-        .write("#! /bin/env sh\n\n".as_bytes())
-        .unwrap();
-    execution_file
-        .write_fmt(format_args!(
-            "# rcc -o {:?} -c {} --src {:?} --dest {:?}\n",
-            cli.output, command, cli.src, cli.dest
-        ))
-        .unwrap();
-    execution_file
-        .write_fmt(format_args!("\n# Total saved space: {}\n", size_str))
-        .unwrap();
-    execution_file
-        .write_fmt(format_args!("\n# Total actions: {}\n", actions.len()))
-        .unwrap();
-    for action in actions {
-        execution_file
-            .write_fmt(format_args!(
-                "\n# destination: {:?}\n",
-                action.dest_entry.path
-            ))
-            .unwrap();
-        match action.action {
-            FileCommand::Move => todo!(),
-            FileCommand::Copy => todo!(),
-            FileCommand::Delete => {
-                execution_file
-                    .write_fmt(format_args!("rm {:?}\n", action.entry.path))
-                    .unwrap();
-            }
-            FileCommand::Print => todo!(),
-        }
+/// Writes absolute paths separated by NUL bytes, as consumed by `xargs -0` or `rsync --from0`.
+fn write_null_delimited(writer: &mut impl Write, paths: &[&Path]) -> io::Result<()> {
+    for path in paths {
+        let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        writer.write_all(path.as_os_str().as_encoded_bytes())?;
+        writer.write_all(b"\0")?;
     }
-    let mut perms = execution_file.metadata().unwrap().permissions();
+    writer.flush()
+}
+
+fn make_executable(file: &File) {
+    let mut perms = file.metadata().unwrap().permissions();
     let mode = perms.mode();
     perms.set_mode(mode | 0o1 /* execute */);
-    execution_file.set_permissions(perms).unwrap();
-    execution_file.flush().unwrap();
-}
-
-fn scan_directories(dir_paths: &Vec<PathBuf>, is_dest: bool, cli: &Cli) -> Vec<Entry> {
-    let mut paths: Vec<PathBuf> = Vec::new();
-    for path in dir_paths {
-        visit_dirs(
-            path.to_path_buf(),
-            &mut paths,
-            0,
-            cli.exclude.clone(),
-            cli.flip_exclusion,
-            cli.include_videos,
-            false,
-        )
-        .expect("Failed to iterate over directories");
+    file.set_permissions(perms).unwrap();
+}
+
+fn scan_directories(
+    dir_paths: &Vec<PathBuf>,
+    is_dest: bool,
+    cli: &Cli,
+    stats: &mut Stats,
+) -> Vec<Entry> {
+    let visit_options = VisitOptions::from(cli);
+    let mut paths: Vec<(PathBuf, PathBuf)> = Vec::new();
+    for root in dir_paths {
+        let mut root_paths: Vec<PathBuf> = Vec::new();
+        if root.as_os_str() == STDIN_PATH {
+            read_stdin_paths(&mut root_paths, &visit_options)
+                .expect("Failed to read paths from stdin");
+        } else {
+            visit_dirs(root.to_path_buf(), &mut root_paths, 0, &visit_options)
+                .expect("Failed to iterate over directories");
+        }
+        paths.extend(root_paths.into_iter().map(|path| (root.clone(), path)));
+    }
+    // Listed files may already be gone after running an earlier script
+    paths.retain(|(_, path)| path_exists(path.clone()));
+    // Only new imports are compared, the destination is always scanned in full
+    if !is_dest && let Some(since) = cli.since {
+        let found = paths.len();
+        paths.retain(|(_, path)| modified_since(path, since));
+        if !cli.quiet {
+            log!(
+                "Skipping {} files not modified since {}",
+                found - paths.len(),
+                since.with_timezone(&Local).format(SINCE_DATE_TIME_FORMAT)
+            );
+        }
     }
     let mut entries = Vec::new();
-    println!("Found files {:?}", paths.len());
-    for path in paths {
-        let res: Result<CollectedMetadata> = get_metadata_nom(&path, &cli);
+    if !cli.quiet {
+        log!("Found files {:?}", paths.len());
+    }
+    stats.files_scanned += paths.len();
+    let results: Vec<(PathBuf, PathBuf, Result<CollectedMetadata>)> = paths
+        .into_par_iter()
+        .map(|(root, path)| {
+            let res = get_metadata_nom(&path, cli);
+            (root, path, res)
+        })
+        .collect();
+    for (root, path, res) in results {
         let Ok(metadata) = res else {
-            println!(
-                "Skipping {path:?} due to {}",
-                res.err().unwrap_or(anyhow!("Unknown error")).to_string()
-            );
+            if !cli.quiet {
+                log!(
+                    "{}",
+                    format!(
+                        "Skipping {path:?} due to {}",
+                        res.err().unwrap_or(anyhow!("Unknown error"))
+                    )
+                    .yellow()
+                );
+            }
+            stats.skipped_files += 1;
             continue;
         };
 
-        let entry = Entry {
-            path,
-            metadata,
-            is_dest,
-        };
+        if metadata.image_metadata.is_some() {
+            stats.image_files += 1;
+        }
+        if metadata.video_metadata.is_some() {
+            stats.video_files += 1;
+        }
+        stats.bytes_scanned += metadata.file_metadata.file_size;
+
+        let entry = Entry::new(path, root, metadata, is_dest);
 
-        println!("Adding: {}", entry);
+        if !cli.quiet {
+            log!("Adding: {}", entry);
+        }
 
         entries.push(entry)
 
@@ -534,64 +1710,193 @@ fn scan_directories(dir_paths: &Vec<PathBuf>, is_dest: bool, cli: &Cli) -> Vec<E
     entries
 }
 
-fn visit_dirs(
-    dir: PathBuf,
-    paths: &mut Vec<PathBuf>,
-    depth: i32,
+fn read_stdin_paths(paths: &mut Vec<PathBuf>, options: &VisitOptions) -> io::Result<()> {
+    for line in io::stdin().lines() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        let path = PathBuf::from(line);
+        if is_path_included(&path, None, options) {
+            paths.push(path);
+        } else if !options.quiet {
+            log!("{}", format!("Skipping {path:?}").yellow());
+        }
+    }
+    Ok(())
+}
+
+/// Traversal options shared by every level of `visit_dirs`.
+struct VisitOptions {
     excluded_paths: Vec<String>,
     flip_exclusion: bool,
     include_videos: bool,
+    include_hidden: bool,
+    excluded_extensions: Vec<String>,
     print_directories: bool,
+    quiet: bool,
+}
+
+impl From<&Cli> for VisitOptions {
+    fn from(cli: &Cli) -> Self {
+        VisitOptions {
+            excluded_paths: cli.exclude.clone(),
+            flip_exclusion: cli.flip_exclusion,
+            include_videos: cli.include_videos,
+            include_hidden: cli.include_hidden,
+            excluded_extensions: cli.exclude_extension.clone(),
+            print_directories: cli.show_included,
+            quiet: cli.quiet,
+        }
+    }
+}
+
+fn visit_dirs(
+    dir: PathBuf,
+    paths: &mut Vec<PathBuf>,
+    depth: i32,
+    options: &VisitOptions,
 ) -> io::Result<()> {
     if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
+        // Unreadable directories are skipped so the rest of the tree is still scanned
+        let dir_entries = match fs::read_dir(&dir) {
+            Ok(dir_entries) => dir_entries,
+            Err(err) => {
+                log!("{}", format!("Unable to read {dir:?}: {err}").yellow());
+                return Ok(());
+            }
+        };
+        for entry in dir_entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    log!(
+                        "{}",
+                        format!("Unable to read entry in {dir:?}: {err}").yellow()
+                    );
+                    continue;
+                }
+            };
             let path = entry.path();
             if path.is_dir() {
                 let dir_name = path
                     .as_path()
                     .file_name()
                     .expect("Could not get relative path")
-                    .to_str()
-                    .unwrap();
-                let mut filter_res = filter_string(dir_name, excluded_paths.clone());
-                if flip_exclusion {
+                    .to_string_lossy();
+                let mut filter_res = filter_string(&dir_name, options.excluded_paths.clone());
+                if options.flip_exclusion {
                     filter_res = !filter_res;
                 }
-                if (depth != 0 || filter_res) && !dir_name.starts_with(".") {
+                if (depth != 0 || filter_res)
+                    && (options.include_hidden || !dir_name.starts_with("."))
+                {
                     // filter
-                    if print_directories && depth == 0 {
-                        println!("Including {dir_name}");
+                    if options.print_directories && depth == 0 {
+                        log!("Including {dir_name}");
                     }
-                    visit_dirs(
-                        path,
-                        paths,
-                        depth + 1,
-                        excluded_paths.clone(),
-                        flip_exclusion,
-                        include_videos,
-                        print_directories,
-                    )?;
+                    visit_dirs(path, paths, depth + 1, options)?;
                 }
             } else {
                 let path_buf = entry.path();
-                if is_file_allowed(&path_buf, include_videos) {
+                if is_file_allowed(
+                    &path_buf,
+                    options.include_videos,
+                    options.include_hidden,
+                    &options.excluded_extensions,
+                ) {
                     // println!("Adding {path_buf:?}");
                     paths.push(path_buf);
-                } else {
-                    println!("Skipping {path_buf:?}");
+                } else if !options.quiet {
+                    log!("{}", format!("Skipping {path_buf:?}").yellow());
                 }
             }
         }
     } else if dir.is_file() {
         paths.push(dir);
     } else {
-        println!("unknown {dir:?}");
+        log!("{}", format!("unknown {dir:?}").red());
     }
 
     Ok(())
 }
 
+/// Parses `--since` as either an age like `12h`, `3d` or `2w`, or a local date like
+/// `2024-05-01` or `2024-05-01 18:30:00`.
+fn parse_since(value: &str) -> Result<DateTime<Utc>, String> {
+    let (amount, unit) =
+        value.split_at(value.len() - value.chars().last().map_or(0, char::len_utf8));
+    if let Ok(amount) = amount.parse::<u32>() {
+        let seconds = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            "w" => 7 * 24 * 60 * 60,
+            _ => return Err(format!("unknown unit {unit:?}, expected s, m, h, d or w")),
+        };
+        return Ok(Utc::now() - Duration::from_secs(amount as u64 * seconds));
+    }
+    let date = NaiveDateTime::parse_from_str(value, SINCE_DATE_TIME_FORMAT)
+        .or_else(|_| {
+            NaiveDate::parse_from_str(value, SINCE_DATE_FORMAT)
+                .map(|date| date.and_hms_opt(0, 0, 0).unwrap())
+        })
+        .map_err(|_| format!("expected an age like 3d or a date like 2024-05-01, got {value:?}"))?;
+    date.and_local_timezone(Local)
+        .earliest()
+        .map(|date| date.to_utc())
+        .ok_or(format!("{value:?} doesn't exist in the local timezone"))
+}
+
+fn modified_since(path: &Path, since: DateTime<Utc>) -> bool {
+    path.metadata()
+        .is_ok_and(|metadata| metadata.mtime() >= since.timestamp())
+}
+
+/// Whether a file found outside `visit_dirs`, e.g. by `--watch` or listed on stdin, passes the
+/// same filters as a scanned one under `root`.
+fn is_path_included(path: &Path, root: Option<&Path>, options: &VisitOptions) -> bool {
+    let relative = root
+        .and_then(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path);
+    let dir_names: Vec<String> = relative
+        .parent()
+        .map(|parent| {
+            parent
+                .components()
+                .filter_map(|component| match component {
+                    Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    if !options.include_hidden && dir_names.iter().any(|name| name.starts_with(".")) {
+        return false;
+    }
+    // Like `visit_dirs`, only the top-level directory is matched against `--exclude`. Listed
+    // paths have no root to be relative to, so any of their directories can match.
+    let matched_dirs = match root {
+        Some(_) => &dir_names[..dir_names.len().min(1)],
+        None => &dir_names[..],
+    };
+    if !matched_dirs.is_empty() {
+        let excluded = matched_dirs
+            .iter()
+            .any(|name| !filter_string(name, options.excluded_paths.clone()));
+        if excluded != options.flip_exclusion {
+            return false;
+        }
+    }
+    is_file_allowed(
+        &path.to_path_buf(),
+        options.include_videos,
+        options.include_hidden,
+        &options.excluded_extensions,
+    )
+}
+
 fn filter_string(string: &str, excluded_paths: Vec<String>) -> bool {
     for path in excluded_paths {
         if string.contains(&path) {
@@ -605,12 +1910,18 @@ fn path_exists(path: PathBuf) -> bool {
     fs::metadata(path).is_ok()
 }
 
-fn is_file_allowed(filename: &PathBuf, include_videos: bool) -> bool {
-    if filename
-        .file_name()
-        .unwrap()
-        .to_string_lossy()
-        .starts_with(".")
+fn is_file_allowed(
+    filename: &PathBuf,
+    include_videos: bool,
+    include_hidden: bool,
+    excluded_extensions: &[String],
+) -> bool {
+    if !include_hidden
+        && filename
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".")
     {
         return false;
     }
@@ -618,23 +1929,17 @@ fn is_file_allowed(filename: &PathBuf, include_videos: bool) -> bool {
     let ext = filename
         .extension()
         .unwrap_or(OsStr::new(""))
-        .to_str()
-        .unwrap();
+        .to_string_lossy();
     let lower_passed = ext.to_lowercase();
 
-    let mut ext: Vec<&str> = IMAGE_EXTENSIONS.to_vec();
-
-    if include_videos {
-        ext.extend(VIDEOS_EXTENSIONS.iter());
+    if excluded_extensions
+        .iter()
+        .any(|excluded| excluded.trim_start_matches('.').to_lowercase() == lower_passed)
+    {
+        return false;
     }
 
-    for allowed_extension in ext {
-        let lower_allowed = allowed_extension.to_lowercase();
-        if lower_allowed == lower_passed {
-            return true;
-        }
-    }
-    false
+    is_image_extension(&lower_passed) || (include_videos && is_video_extension(&lower_passed))
 }
 
 fn get_file_metadata(filename: &PathBuf) -> Result<FileMetadata> {
@@ -644,33 +1949,134 @@ fn get_file_metadata(filename: &PathBuf) -> Result<FileMetadata> {
 
     let extension = filename
         .extension()
-        .unwrap()
-        .to_os_string()
-        .into_string()
-        .unwrap()
+        .ok_or(Error::msg("File has no extension"))?
+        .to_string_lossy()
         .to_lowercase();
     let base_file_name = filename
         .file_name()
         .ok_or(Error::msg("File metadata read error"))?
-        .to_os_string()
-        .into_string()
-        .unwrap();
+        .to_string_lossy()
+        .into_owned();
     let file_size = filename.metadata()?.size();
+    if file_size == 0 {
+        bail!("empty file");
+    }
+    let min_size = if is_video(filename) {
+        MIN_VIDEO_SIZE
+    } else {
+        MIN_IMAGE_SIZE
+    };
+    if file_size < min_size {
+        bail!("truncated file ({} bytes)", file_size);
+    }
     let creation_date = filename
         .metadata()?
         .created()
         .ok()
-        .map(|t| format!("{:?}", t));
+        .map(DateTime::<Utc>::from);
+    let edit_sidecar = has_edit_sidecar(filename);
 
     Ok(FileMetadata {
         extension,
         base_file_name,
         file_size,
         creation_date,
+        edit_sidecar,
+        sample_hash: None,
+        digest: None,
     })
 }
 
-fn get_image_metadata(filename: &PathBuf) -> Result<ImageMetadata> {
+/// Sidecars like `IMG_1234.xmp`, `IMG_1234.jpg.xmp` or `IMG_1234.aae` that are left
+/// without a file once `path` and the other `removed` files are gone.
+fn orphaned_sidecars(path: &Path, removed: &HashSet<PathBuf>) -> Vec<PathBuf> {
+    let (Some(dir), Some(stem), Some(file_name)) =
+        (path.parent(), path.file_stem(), path.file_name())
+    else {
+        return vec![];
+    };
+    let Ok(dir_entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    let mut sidecars = vec![];
+    let mut has_other_owner = false;
+    for other in dir_entries.flatten().map(|entry| entry.path()) {
+        if other == path {
+            continue;
+        }
+        let is_sidecar = other.extension().is_some_and(|ext| {
+            SIDECAR_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())
+        });
+        if is_sidecar && (other.file_stem() == Some(stem) || other.file_stem() == Some(file_name)) {
+            sidecars.push(other);
+        } else if !is_sidecar && other.file_stem() == Some(stem) && !removed.contains(&other) {
+            has_other_owner = true;
+        }
+    }
+    // `IMG_1234.xmp` still belongs to a remaining `IMG_1234.ARW`
+    if has_other_owner {
+        sidecars.retain(|sidecar| sidecar.file_stem() == Some(file_name));
+    }
+    sidecars
+}
+
+/// Short summary of the metadata an entry was matched on, for script comments.
+fn describe_entry(entry: &Entry) -> String {
+    let metadata = &entry.metadata;
+    let mut parts = vec![];
+    if let Some(image) = &metadata.image_metadata {
+        parts.push(image.date.clone());
+    } else if let Some(video) = &metadata.video_metadata {
+        parts.push(video.date.clone());
+    }
+    parts.push(stats::format_size(metadata.file_metadata.file_size));
+    if let Some(image) = &metadata.image_metadata {
+        if let Some(model) = &image.model {
+            parts.push(model.clone());
+        }
+        if let Some((x, y)) = image.resolution {
+            parts.push(format!("{}x{}", x, y));
+        }
+    }
+    if let Some(duration) = metadata
+        .video_metadata
+        .as_ref()
+        .and_then(|video| video.video_duration)
+    {
+        parts.push(format!("{:.2?}", duration));
+    }
+    parts.join(", ")
+}
+
+fn sidecar_entry(path: PathBuf, owner: &Entry) -> Entry {
+    let file_metadata = FileMetadata {
+        base_file_name: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        file_size: path.metadata().map(|m| m.len()).unwrap_or(0),
+        extension: path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+    let metadata = CollectedMetadata {
+        file_metadata,
+        ..Default::default()
+    };
+    Entry::new(path, owner.root.clone(), metadata, owner.is_dest)
+}
+
+/// iOS keeps non-destructive edits in an `.aae` file next to the original.
+fn has_edit_sidecar(filename: &Path) -> bool {
+    AAE_EXTENSIONS
+        .iter()
+        .any(|extension| path_exists(filename.with_extension(extension)))
+}
+
+fn get_image_metadata(filename: &PathBuf, allow_mtime_date: bool) -> Result<ImageMetadata> {
     if !path_exists(filename.clone()) {
         anyhow::bail!("File doesn't exist");
     }
@@ -678,8 +2084,29 @@ fn get_image_metadata(filename: &PathBuf) -> Result<ImageMetadata> {
     let mut image_meta = ImageMetadata::default();
 
     assert!(!is_video(&filename));
-    let meta = Metadata::new_from_path(filename)?;
-    image_meta.date = meta.get_tag_string("Exif.Photo.DateTimeOriginal")?;
+    if !REXIV2_AVAILABLE.load(Ordering::Relaxed) {
+        return get_image_metadata_nom(filename, allow_mtime_date);
+    }
+    let meta = match Metadata::new_from_path(filename) {
+        Ok(meta) => meta,
+        // Some newer HEIC files can't be opened by gexiv2, nom-exif handles them
+        Err(err) => {
+            return get_image_metadata_nom(filename, allow_mtime_date).map_err(|_| err.into());
+        }
+    };
+    let date = EXIF_DATE_TAGS
+        .iter()
+        .find_map(|(tag, source)| meta.get_tag_string(tag).ok().map(|date| (date, *source)));
+    (image_meta.date, image_meta.date_source) = match date {
+        Some(date) => date,
+        None if allow_mtime_date => (mtime_date(filename)?, DateSource::FileTime),
+        None => bail!("no DateTimeOriginal, DateTimeDigitized or DateTime"),
+    };
+    image_meta.sub_sec = meta
+        .get_tag_string("Exif.Photo.SubSecTimeOriginal")
+        .or_else(|_| meta.get_tag_string("Exif.Photo.SubSecTime"))
+        .ok()
+        .and_then(|sub_sec| normalize_sub_sec(&sub_sec));
     let xres = meta.get_tag_rational("Exif.Photo.PixelXDimension");
     let yres = meta.get_tag_rational("Exif.Photo.PixelYDimension");
     if xres.is_some() && yres.is_some() {
@@ -689,11 +2116,97 @@ fn get_image_metadata(filename: &PathBuf) -> Result<ImageMetadata> {
         //     println!("tag: {:?} val: {:?}", tag, meta.get_tag_interpreted_string(tag.as_str()));
         // }
     }
+    image_meta.make = meta.get_tag_string("Exif.Image.Make").ok();
     image_meta.model = meta.get_tag_string("Exif.Image.Model").ok();
-    image_meta.brightness = meta.get_tag_string("Exif.Photo.BrightnessValue").ok();
+    image_meta.lens = meta.get_tag_string("Exif.Photo.LensModel").ok();
+    image_meta.brightness = meta.get_tag_rational("Exif.Photo.BrightnessValue");
+    image_meta.gps = meta
+        .get_gps_info()
+        .map(|gps| GpsPosition::from_degrees(gps.latitude, gps.longitude));
+    image_meta.thumbnail_hash = meta.get_thumbnail().map(Sha256::digest);
+    Ok(image_meta)
+}
+
+fn get_image_metadata_nom(filename: &PathBuf, allow_mtime_date: bool) -> Result<ImageMetadata> {
+    let mut image_meta = ImageMetadata::default();
+
+    let mut parser = MediaParser::new();
+    let ms = MediaSource::file_path(filename)?;
+    if !ms.has_exif() {
+        bail!("no exif data");
+    }
+    let iter: ExifIter = parser.parse(ms)?;
+    let exif: Exif = iter.into();
+
+    // Keep the same format as gexiv2 so dates compare equal across both readers
+    let exif_date = |tag: ExifTag| match exif.get(tag) {
+        Some(EntryValue::Time(time)) => {
+            Some(time.naive_local().format(EXIF_DATE_FORMAT).to_string())
+        }
+        Some(EntryValue::NaiveDateTime(time)) => Some(time.format(EXIF_DATE_FORMAT).to_string()),
+        _ => None,
+    };
+    let date = [
+        (ExifTag::DateTimeOriginal, DateSource::Original),
+        (ExifTag::CreateDate, DateSource::Digitized),
+        (ExifTag::ModifyDate, DateSource::Modified),
+    ]
+    .into_iter()
+    .find_map(|(tag, source)| exif_date(tag).map(|date| (date, source)));
+    (image_meta.date, image_meta.date_source) = match date {
+        Some(date) => date,
+        None if allow_mtime_date => (mtime_date(filename)?, DateSource::FileTime),
+        None => bail!("no DateTimeOriginal, DateTimeDigitized or DateTime"),
+    };
+
+    let dimension = |tag: ExifTag| {
+        let value = exif.get(tag)?;
+        let value = value.as_u32().or_else(|| value.as_u16().map(u32::from))?;
+        Some(Ratio::from_integer(i32::try_from(value).ok()?))
+    };
+    if let (Some(x), Some(y)) = (
+        dimension(ExifTag::ExifImageWidth),
+        dimension(ExifTag::ExifImageHeight),
+    ) {
+        image_meta.resolution = Some((x, y));
+    }
+    let string = |tag: ExifTag| exif.get(tag).and_then(|v| v.as_str()).map(str::to_string);
+    image_meta.sub_sec = string(ExifTag::SubSecTimeOriginal)
+        .or_else(|| string(ExifTag::SubSecTime))
+        .and_then(|sub_sec| normalize_sub_sec(&sub_sec));
+    image_meta.make = string(ExifTag::Make);
+    image_meta.model = string(ExifTag::Model);
+    image_meta.lens = string(ExifTag::LensModel);
+    image_meta.brightness = exif
+        .get(ExifTag::BrightnessValue)
+        .and_then(|v| v.as_irational())
+        .map(|r| Ratio::new_raw(r.0, r.1));
+    image_meta.gps = exif.get_gps_info().ok().flatten().map(|gps| {
+        let degrees = |value: &LatLng, reference: char, negative: char| {
+            let degrees =
+                value.0.as_float() + value.1.as_float() / 60.0 + value.2.as_float() / 3600.0;
+            if reference == negative {
+                -degrees
+            } else {
+                degrees
+            }
+        };
+        GpsPosition::from_degrees(
+            degrees(&gps.latitude, gps.latitude_ref, 'S'),
+            degrees(&gps.longitude, gps.longitude_ref, 'W'),
+        )
+    });
     Ok(image_meta)
 }
 
+/// Formats the file modification time like an EXIF date, for files that have none.
+fn mtime_date(filename: &Path) -> Result<String> {
+    let modified = filename.metadata()?.modified()?;
+    Ok(DateTime::<Local>::from(modified)
+        .format(EXIF_DATE_FORMAT)
+        .to_string())
+}
+
 fn get_video_metadata(filename: &PathBuf) -> Result<VideoMetadata> {
     if !path_exists(filename.clone()) {
         anyhow::bail!("File doesn't exist");
@@ -703,78 +2216,233 @@ fn get_video_metadata(filename: &PathBuf) -> Result<VideoMetadata> {
 
     let mut parser = MediaParser::new();
     let ms = MediaSource::file_path(filename)?;
-    assert!(ms.has_track());
+    if !ms.has_track() {
+        bail!("no track info");
+    }
     let track_info: TrackInfo = parser.parse(ms)?;
     video_meta.video_duration = track_info
         .get(TrackInfoTag::DurationMs)
-        .map(|f| Duration::from_millis(f.as_u64().unwrap()));
+        .and_then(|f| f.as_u64())
+        .map(Duration::from_millis);
     video_meta.date = track_info
         .get(TrackInfoTag::CreateDate)
-        .map(|f| f.as_time().unwrap().to_rfc3339())
-        .unwrap();
+        .and_then(|f| f.as_time())
+        .map(|t| t.to_rfc3339())
+        .ok_or(Error::msg("no creation date"))?;
 
     return Ok(video_meta);
 }
 
-fn is_video(path: &Path) -> bool {
-    let extension = path
-        .extension()
-        .unwrap_or_default()
-        .to_str()
-        .unwrap_or("")
-        .to_lowercase();
-    VIDEOS_EXTENSIONS.contains(&extension.as_str())
+fn get_avi_metadata(filename: &Path) -> Result<VideoMetadata> {
+    let info = avi::read_avi_info(filename)?;
+    // Old camcorders often don't record a date, the file time is the best guess left
+    let date = match info.date {
+        Some(date) => date,
+        None => DateTime::<Local>::from(filename.metadata()?.modified()?).naive_local(),
+    };
+    Ok(VideoMetadata {
+        date: date.format(AVI_DATE_FORMAT).to_string(),
+        video_duration: info.duration,
+        // AVI streams aren't inspected
+        audio: AudioTrack::Unknown,
+    })
 }
 
 fn get_metadata_nom(filename: &PathBuf, cli: &Cli) -> Result<CollectedMetadata> {
-    let file_metadata = get_file_metadata(filename)?;
-    let image_metadata;
-    let video_metadata;
-    let sha_metadata;
+    let mut file_metadata = get_file_metadata(filename)?;
+    if let Some(sample_bytes) = cli.sample_bytes {
+        file_metadata.sample_hash = Some(compute_sample_sha256(filename, sample_bytes)?);
+    }
 
     // println!("file: {:?}", filename);
-    if file_metadata.extension == "mp4" {
-        image_metadata = None;
-        video_metadata = Some(get_mp4_metadata(filename)?);
-    } else if VIDEOS_EXTENSIONS.contains(&file_metadata.extension.as_str()) {
-        image_metadata = None;
-        video_metadata = Some(get_video_metadata(filename)?);
+    let extracted = if file_metadata.extension == "mp4" {
+        get_mp4_metadata(filename).map(|video| (None, Some(video)))
+    } else if file_metadata.extension == "avi" {
+        get_avi_metadata(filename).map(|video| (None, Some(video)))
+    } else if is_video_extension(&file_metadata.extension) {
+        get_video_metadata(filename).map(|video| (None, Some(video)))
     } else {
-        image_metadata = Some(get_image_metadata(filename)?);
-        video_metadata = None;
+        get_image_metadata(filename, cli.allow_mtime_date).map(|image| (Some(image), None))
     };
-
-    if cli.mode == CompareMode::Exact {
-        sha_metadata = compute_file_sha256(filename).ok();
-    } else {
-        sha_metadata = None;
+    let (image_metadata, video_metadata) = match extracted {
+        Ok(extracted) => extracted,
+        // The file can still be matched on its contents
+        Err(_) if cli.missing_metadata == MissingMetadata::Hash => (None, None),
+        Err(err) => return Err(err),
+    };
+    // Motion photos carry a short video next to the still, both are compared
+    let video_metadata = match video_metadata {
+        None if cli.motion_photos && image_metadata.is_some() => {
+            get_embedded_video_metadata(filename)
+        }
+        video_metadata => video_metadata,
+    };
+    let video_metadata = video_metadata.map(|mut video| {
+        if cli.compare_audio && video.audio == AudioTrack::Unknown {
+            video.audio = read_audio_track(filename);
+        }
+        video
+    });
+
+    // A manifest has to tell modified files apart even when their metadata is unchanged
+    let manifest_requested = cli.manifest.is_some() || cli.compare_manifest.is_some();
+    if cli.mode == CompareMode::Exact
+        || manifest_requested
+        || (image_metadata.is_none() && video_metadata.is_none())
+    {
+        file_metadata.digest = hashing::hash_file(filename, cli.hash_algorithm).ok();
     }
+    let content_hash = if cli.content_wins {
+        content::content_hash(filename).ok()
+    } else {
+        None
+    };
+    let perceptual_hash = if cli.hamming.is_some() && image_metadata.is_some() {
+        perceptual::difference_hash(filename).ok()
+    } else {
+        None
+    };
 
     Ok(CollectedMetadata {
         file_metadata,
         image_metadata,
         video_metadata,
-        sha_metadata,
+        content_hash,
+        perceptual_hash,
     })
 }
 
 fn get_mp4_metadata(filename: &PathBuf) -> Result<VideoMetadata> {
     let f = File::open(filename)?;
     let size = f.metadata()?.len();
-    let reader = BufReader::new(f);
+    read_mp4_metadata(BufReader::new(f), size)
+}
+
+/// Video metadata of a motion photo, read from the MP4 appended to the still.
+fn get_embedded_video_metadata(filename: &Path) -> Option<VideoMetadata> {
+    let video = motion::find_embedded_video(filename).ok()??;
+    let size = video.len() as u64;
+    read_mp4_metadata(io::Cursor::new(video), size).ok()
+}
+
+fn read_mp4_metadata(reader: impl Read + Seek, size: u64) -> Result<VideoMetadata> {
     let mp4 = mp4::Mp4Reader::read_header(reader, size)?;
 
-    if mp4.moov.mvhd.creation_time == 0 {
+    let Some(dt) = mp4_creation_date(mp4.moov.mvhd.creation_time) else {
         bail!("no creation time");
-    }
-    let timestamp = if mp4.moov.mvhd.creation_time > MP4_TO_UNIX_OFFSET {
-        mp4.moov.mvhd.creation_time - MP4_TO_UNIX_OFFSET
-    } else {
-        mp4.moov.mvhd.creation_time
     };
-    let dt = DateTime::from_timestamp(timestamp.try_into().unwrap(), 0).expect("invalid timestamp");
     Ok(VideoMetadata {
         date: dt.to_string(),
         video_duration: Some(mp4.duration()),
+        audio: mp4_audio_track(&mp4),
     })
 }
+
+fn mp4_audio_track<R: Read + Seek>(mp4: &mp4::Mp4Reader<R>) -> AudioTrack {
+    let audio = mp4
+        .tracks()
+        .values()
+        .find(|track| matches!(track.track_type(), Ok(mp4::TrackType::Audio)));
+    match audio {
+        Some(track) => AudioTrack::Present {
+            duration: track.duration(),
+            sample_rate: track.sample_freq_index().ok().map(|index| index.freq()),
+        },
+        None => AudioTrack::Silent,
+    }
+}
+
+/// Reads the audio track of a MOV or other ISO media file that nom-exif parsed without it.
+fn read_audio_track(filename: &Path) -> AudioTrack {
+    let Ok(f) = File::open(filename) else {
+        return AudioTrack::Unknown;
+    };
+    let Ok(size) = f.metadata().map(|metadata| metadata.len()) else {
+        return AudioTrack::Unknown;
+    };
+    match mp4::Mp4Reader::read_header(BufReader::new(f), size) {
+        Ok(mp4) => mp4_audio_track(&mp4),
+        Err(_) => AudioTrack::Unknown,
+    }
+}
+
+/// MP4 times are always seconds since 1904-01-01 UTC, 0 means no time was recorded.
+fn mp4_creation_date(creation_time: u64) -> Option<DateTime<Utc>> {
+    if creation_time == 0 {
+        return None;
+    }
+    DateTime::from_timestamp(creation_time as i64 - MP4_TO_UNIX_OFFSET as i64, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn visit_options(excluded_paths: &[&str]) -> VisitOptions {
+        VisitOptions {
+            excluded_paths: excluded_paths.iter().map(|path| path.to_string()).collect(),
+            flip_exclusion: false,
+            include_videos: true,
+            include_hidden: false,
+            excluded_extensions: vec![],
+            print_directories: false,
+            quiet: true,
+        }
+    }
+
+    #[test]
+    fn listed_paths_are_filtered_like_scanned_ones() {
+        let options = visit_options(&["Trash"]);
+        assert!(is_path_included(
+            Path::new("/photos/2024/IMG_0001.JPG"),
+            None,
+            &options
+        ));
+        assert!(!is_path_included(
+            Path::new("/photos/Trash/IMG_0001.jpg"),
+            None,
+            &options
+        ));
+        assert!(!is_path_included(
+            Path::new("/photos/.cache/IMG_0001.jpg"),
+            None,
+            &options
+        ));
+        assert!(!is_path_included(
+            Path::new("/photos/notes.txt"),
+            None,
+            &options
+        ));
+    }
+
+    #[test]
+    fn watched_paths_only_match_top_level_exclusions() {
+        let options = visit_options(&["Trash"]);
+        let root = Some(Path::new("/Trash"));
+        assert!(is_path_included(
+            Path::new("/Trash/2024/IMG_0001.jpg"),
+            root,
+            &options
+        ));
+        assert!(!is_path_included(
+            Path::new("/Trash/Trash/IMG_0001.jpg"),
+            root,
+            &options
+        ));
+    }
+
+    #[test]
+    fn mp4_creation_date_converts_from_1904_epoch() {
+        let date = mp4_creation_date(3_700_000_000).unwrap();
+        assert_eq!(date.to_rfc3339(), "2021-03-31T01:46:40+00:00");
+    }
+
+    #[test]
+    fn mp4_creation_date_before_unix_epoch() {
+        assert_eq!(mp4_creation_date(0), None);
+        let date = mp4_creation_date(MP4_TO_UNIX_OFFSET - 1).unwrap();
+        assert_eq!(date.to_rfc3339(), "1969-12-31T23:59:59+00:00");
+        let date = mp4_creation_date(1).unwrap();
+        assert_eq!(date.to_rfc3339(), "1904-01-01T00:00:01+00:00");
+    }
+}
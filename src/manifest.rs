@@ -0,0 +1,130 @@
+use anyhow::{Result, anyhow};
+use colored::Colorize;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::Entry;
+use crate::hashing::FileDigest;
+
+const MANIFEST_HEADER: &str = "# rcc manifest: hash, size, date, path";
+const MISSING_FIELD: &str = "-";
+
+#[derive(PartialEq)]
+struct ManifestEntry {
+    hash: String,
+    size: u64,
+    date: String,
+}
+
+/// Snapshot of all scanned files, used to see what changed between two runs.
+#[derive(Default)]
+pub struct Manifest {
+    entries: BTreeMap<String, ManifestEntry>,
+}
+
+impl Manifest {
+    pub fn from_entries<'a>(entries: impl Iterator<Item = &'a Entry>) -> Manifest {
+        let mut manifest = Manifest::default();
+        for entry in entries {
+            let metadata = &entry.metadata;
+            // Always computed when a manifest is requested, missing only for unreadable files
+            let hash = metadata
+                .file_metadata
+                .digest
+                .as_ref()
+                .map(FileDigest::to_string)
+                .unwrap_or(MISSING_FIELD.to_string());
+            let date = metadata
+                .image_metadata
+                .as_ref()
+                .map(|image| image.date.clone())
+                .or(metadata
+                    .video_metadata
+                    .as_ref()
+                    .map(|video| video.date.clone()))
+                .unwrap_or(MISSING_FIELD.to_string());
+            manifest.entries.insert(
+                entry.path.to_string_lossy().to_string(),
+                ManifestEntry {
+                    hash,
+                    size: metadata.file_metadata.file_size,
+                    date,
+                },
+            );
+        }
+        manifest
+    }
+
+    pub fn load(path: &Path) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        for line in fs::read_to_string(path)?.lines() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.splitn(4, '\t');
+            let mut next_field = || {
+                fields
+                    .next()
+                    .ok_or(anyhow!("Malformed manifest line: {}", line))
+            };
+            let hash = next_field()?.to_string();
+            let size = next_field()?.parse()?;
+            let date = next_field()?.to_string();
+            let path = next_field()?.to_string();
+            manifest
+                .entries
+                .insert(path, ManifestEntry { hash, size, date });
+        }
+        Ok(manifest)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", MANIFEST_HEADER)?;
+        for (path, entry) in &self.entries {
+            writeln!(
+                writer,
+                "{}\t{}\t{}\t{}",
+                entry.hash, entry.size, entry.date, path
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Prints files added, removed or modified in `new` compared to `old`.
+pub fn print_diff(old: &Manifest, new: &Manifest) {
+    let added: Vec<&String> = new
+        .entries
+        .keys()
+        .filter(|path| !old.entries.contains_key(*path))
+        .collect();
+    let removed: Vec<&String> = old
+        .entries
+        .keys()
+        .filter(|path| !new.entries.contains_key(*path))
+        .collect();
+    let modified: Vec<&String> = new
+        .entries
+        .iter()
+        .filter(|(path, entry)| old.entries.get(*path).is_some_and(|old| old != *entry))
+        .map(|(path, _)| path)
+        .collect();
+
+    log!("Changes since previous manifest:");
+    log!("  Added:    {}", added.len());
+    for path in added {
+        log!("    {}", path.green());
+    }
+    log!("  Removed:  {}", removed.len());
+    for path in removed {
+        log!("    {}", path.red());
+    }
+    log!("  Modified: {}", modified.len());
+    for path in modified {
+        log!("    {}", path.yellow());
+    }
+}
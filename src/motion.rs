@@ -0,0 +1,21 @@
+use anyhow::Result;
+use std::fs;
+use std::path::Path;
+
+// Brands of the still image container itself, e.g. a HEIC file starts with its own `ftyp` box
+const IMAGE_BRANDS: [&[u8]; 5] = [b"heic", b"heix", b"mif1", b"msf1", b"avif"];
+const BOX_SIZE_FIELD: usize = 4;
+
+/// Finds a video appended to a still image, as written by Google and Samsung motion photos,
+/// and returns it as a standalone MP4.
+pub fn find_embedded_video(path: &Path) -> Result<Option<Vec<u8>>> {
+    let data = fs::read(path)?;
+    let start = data
+        .windows(8)
+        .enumerate()
+        // The first box of the image itself can't be the embedded video
+        .skip(BOX_SIZE_FIELD + 1)
+        .find(|(_, window)| &window[..4] == b"ftyp" && !IMAGE_BRANDS.contains(&&window[4..8]))
+        .map(|(position, _)| position - BOX_SIZE_FIELD);
+    Ok(start.map(|start| data[start..].to_vec()))
+}
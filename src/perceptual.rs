@@ -0,0 +1,24 @@
+use anyhow::Result;
+use image::imageops::FilterType;
+use std::path::Path;
+
+// One column more than the hash has bits per row, each bit compares two neighbours
+const HASH_WIDTH: u32 = 9;
+const HASH_HEIGHT: u32 = 8;
+
+/// Difference hash of an image: it is shrunk to 9x8 grey pixels and every bit records whether
+/// a pixel is brighter than its right neighbour. Resizing and recompression barely change it,
+/// so copies end up a few bits apart while different photos differ in about half of them.
+pub fn difference_hash(path: &Path) -> Result<u64> {
+    let pixels = image::open(path)?
+        .resize_exact(HASH_WIDTH, HASH_HEIGHT, FilterType::Triangle)
+        .to_luma8();
+    let mut hash = 0;
+    for y in 0..HASH_HEIGHT {
+        for x in 0..HASH_WIDTH - 1 {
+            let brighter = pixels.get_pixel(x, y).0[0] > pixels.get_pixel(x + 1, y).0[0];
+            hash = hash << 1 | brighter as u64;
+        }
+    }
+    Ok(hash)
+}
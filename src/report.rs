@@ -0,0 +1,189 @@
+use anyhow::Result;
+use base64::prelude::*;
+use image::{DynamicImage, ImageFormat};
+use rayon::prelude::*;
+use rexiv2::Metadata;
+use serde_json::{Value, json};
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::{CompareMode, DuplicateGroup, Entry, REXIV2_AVAILABLE};
+
+// Matches the size the stylesheet shows them at
+const THUMBNAIL_SIZE: u32 = 160;
+
+pub fn write_html_report(path: &Path, groups: &[DuplicateGroup]) -> Result<()> {
+    // Thumbnails are the slow part, render every group up front in parallel
+    let rendered: Vec<String> = groups.par_iter().map(render_group).collect();
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(
+        b"<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>rcc report</title>\n\
+<style>\n\
+body { font-family: sans-serif; }\n\
+table { border-collapse: collapse; margin-bottom: 2em; }\n\
+td, th { border: 1px solid #ccc; padding: 4px 8px; text-align: left; }\n\
+img { max-width: 160px; max-height: 160px; }\n\
+</style>\n</head>\n<body>\n",
+    )?;
+    file.write_fmt(format_args!(
+        "<h1>Duplicate groups: {}</h1>\n",
+        groups.len()
+    ))?;
+    for group in rendered {
+        file.write_all(group.as_bytes())?;
+    }
+    file.write_all(b"</body>\n</html>\n")?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Writes a single match as one JSON object per line, flushed so it can be consumed right away.
+pub fn write_jsonl_match(
+    writer: &mut impl Write,
+    keeper: &Entry,
+    duplicate: &Entry,
+    mode: CompareMode,
+) -> Result<()> {
+    let line: Value = json!({
+        "keeper": keeper.path.to_string_lossy(),
+        "duplicate": duplicate.path.to_string_lossy(),
+        "keeper_size": keeper.metadata.file_metadata.file_size,
+        "duplicate_size": duplicate.metadata.file_metadata.file_size,
+        "mode": mode.to_string(),
+        "matched": matched_metadata(duplicate),
+    });
+    writeln!(writer, "{}", line)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes all duplicate groups as a single JSON document, without acting on any file.
+pub fn write_json_report(
+    writer: &mut impl Write,
+    groups: &[DuplicateGroup],
+    mode: CompareMode,
+) -> Result<()> {
+    let groups: Vec<Value> = groups
+        .iter()
+        .map(|group| {
+            let duplicates: Vec<Value> = group
+                .duplicates
+                .iter()
+                .map(|duplicate| {
+                    json!({
+                        "path": duplicate.path.to_string_lossy(),
+                        "size": duplicate.metadata.file_metadata.file_size,
+                        "matched": matched_metadata(duplicate),
+                    })
+                })
+                .collect();
+            let mut group_json = json!({
+                "keeper": group.keeper.path.to_string_lossy(),
+                "keeper_size": group.keeper.metadata.file_metadata.file_size,
+                "reclaimable_bytes": group.reclaimable_bytes(),
+                "duplicates": duplicates,
+            });
+            let distances = group.hamming_distances();
+            if !distances.is_empty() {
+                group_json["hamming_distances"] = json!(distances);
+            }
+            group_json
+        })
+        .collect();
+    let report = json!({
+        "mode": mode.to_string(),
+        "groups": groups,
+    });
+    serde_json::to_writer_pretty(&mut *writer, &report)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// The metadata a match was made on.
+fn matched_metadata(entry: &Entry) -> Value {
+    let metadata = &entry.metadata;
+    let mut matched = json!({});
+    if let Some(image) = &metadata.image_metadata {
+        matched["date"] = json!(image.date);
+        matched["model"] = json!(image.model);
+        matched["lens"] = json!(image.lens);
+        matched["resolution"] = json!(image.resolution.map(|(x, y)| format!("{}x{}", x, y)));
+    }
+    if let Some(video) = &metadata.video_metadata {
+        matched["date"] = json!(video.date);
+        matched["duration_ms"] = json!(video.video_duration.map(|d| d.as_millis() as u64));
+    }
+    // Tagged with the algorithm so the hash can be reproduced
+    if let Some(digest) = &metadata.file_metadata.digest {
+        matched["hash"] = json!(digest.to_string());
+    }
+    matched
+}
+
+fn render_group(group: &DuplicateGroup) -> String {
+    let mut html = String::from(
+        "<table>\n<tr><th>Role</th><th>Thumbnail</th><th>Path</th><th>Metadata</th></tr>\n",
+    );
+    html.push_str(&render_row("keeper", &group.keeper));
+    for duplicate in &group.duplicates {
+        html.push_str(&render_row("duplicate", duplicate));
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+fn render_row(role: &str, entry: &Entry) -> String {
+    let thumbnail = thumbnail_data_uri(entry)
+        .map(|uri| format!("<img src=\"{}\">", uri))
+        .unwrap_or_default();
+    format!(
+        "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+        role,
+        thumbnail,
+        escape_html(&entry.path.display().to_string()),
+        escape_html(&entry.metadata.to_string())
+    )
+}
+
+/// Thumbnail rendered from the image itself, so it shows the pixels that were compared even
+/// when an editor left a stale EXIF thumbnail behind. Formats the `image` crate can't decode,
+/// like HEIC, fall back to the embedded thumbnail.
+fn thumbnail_data_uri(entry: &Entry) -> Option<String> {
+    // Videos have no frames decoded
+    entry.metadata.image_metadata.as_ref()?;
+    let jpeg = render_thumbnail(&entry.path).or_else(|| exif_thumbnail(&entry.path))?;
+    Some(format!(
+        "data:image/jpeg;base64,{}",
+        BASE64_STANDARD.encode(jpeg)
+    ))
+}
+
+fn render_thumbnail(path: &Path) -> Option<Vec<u8>> {
+    let thumbnail = image::open(path)
+        .ok()?
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+    // JPEG has no alpha channel
+    let thumbnail = DynamicImage::from(thumbnail.to_rgb8());
+    let mut jpeg = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut jpeg, ImageFormat::Jpeg).ok()?;
+    Some(jpeg.into_inner())
+}
+
+fn exif_thumbnail(path: &Path) -> Option<Vec<u8>> {
+    if !REXIV2_AVAILABLE.load(Ordering::Relaxed) {
+        return None;
+    }
+    let meta = Metadata::new_from_path(path).ok()?;
+    meta.get_thumbnail().map(<[u8]>::to_vec)
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
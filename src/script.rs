@@ -0,0 +1,626 @@
+use clap::ValueEnum;
+use serde_json::{Value, json};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::{Action, Cli, FileCommand, describe_entry, symlink_target};
+
+#[derive(Default, PartialEq, Clone, Copy, ValueEnum)]
+pub enum ScriptFormat {
+    #[default]
+    Sh,
+    Powershell,
+    Json,
+}
+
+impl ScriptFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ScriptFormat::Sh => "sh",
+            ScriptFormat::Powershell => "ps1",
+            ScriptFormat::Json => "json",
+        }
+    }
+
+    pub fn writer(&self) -> Box<dyn ActionWriter> {
+        match self {
+            ScriptFormat::Sh => Box::new(ShWriter),
+            ScriptFormat::Powershell => Box::new(PowerShellWriter),
+            ScriptFormat::Json => Box::new(JsonWriter),
+        }
+    }
+}
+
+/// The run a script is generated for.
+pub struct Invocation<'a> {
+    pub cli: &'a Cli,
+    pub command: FileCommand,
+    // `-` when the script goes to stdout
+    pub script_path: &'a Path,
+    // NUL-delimited paths removed by the rsync command
+    pub file_list_path: &'a Path,
+}
+
+/// Serializes actions in the syntax of one `--script-format`. The actions are decided before
+/// they reach the writer, it only turns them into commands.
+pub trait ActionWriter {
+    /// Written before any action, e.g. the shebang and the invocation.
+    fn write_header(&self, out: &mut dyn Write, invocation: &Invocation) -> io::Result<()>;
+
+    fn write_action(
+        &self,
+        out: &mut dyn Write,
+        action: &Action,
+        invocation: &Invocation,
+    ) -> io::Result<()>;
+
+    /// Written once every action is known, with the totals and the steps that need all of them.
+    fn write_footer(
+        &self,
+        out: &mut dyn Write,
+        actions: &[Action],
+        saved_space: &str,
+        invocation: &Invocation,
+    ) -> io::Result<()>;
+
+    /// Writes the steps reverting `actions`, later actions first.
+    fn write_undo(
+        &self,
+        out: &mut dyn Write,
+        actions: &[Action],
+        invocation: &Invocation,
+    ) -> io::Result<()>;
+
+    /// Whether the output is meant to be run, it is then made executable once complete.
+    fn is_executable(&self) -> bool {
+        true
+    }
+}
+
+pub struct ShWriter;
+
+impl ActionWriter for ShWriter {
+    fn write_header(&self, out: &mut dyn Write, invocation: &Invocation) -> io::Result<()> {
+        out.write_all("#! /bin/env sh\n\n".as_bytes())?;
+        if !invocation.cli.no_comments {
+            write_comment(out, &command_line(invocation, sh_quote))?;
+        }
+        Ok(())
+    }
+
+    fn write_action(
+        &self,
+        out: &mut dyn Write,
+        action: &Action,
+        invocation: &Invocation,
+    ) -> io::Result<()> {
+        write_action_comments(out, action, invocation.cli)?;
+        let path = sh_quote(&action.entry.path);
+        match action.action {
+            FileCommand::Move | FileCommand::Copy => {
+                let target = action.target.as_ref().expect("Move without a target");
+                let command: &[&[u8]] = if action.action == FileCommand::Move {
+                    &[b"mv", b"-n"]
+                } else {
+                    &[b"cp", b"-p", b"-n"]
+                };
+                write_command(
+                    out,
+                    &[
+                        b"mkdir",
+                        b"-p",
+                        &sh_quote(target.parent().unwrap_or(Path::new("."))),
+                    ],
+                )?;
+                let target = sh_quote(target);
+                write_command(out, &[command, &[&path, &target]].concat())
+            }
+            FileCommand::Delete => write_command(out, &[b"rm", b"-f", &path]),
+            FileCommand::Print => write_command(out, &[b"echo", &path]),
+            // Deleted all at once in the footer
+            FileCommand::Rsync => Ok(()),
+            FileCommand::Hardlink => {
+                write_command(out, &[b"rm", b"-f", &path])?;
+                write_command(
+                    out,
+                    &[b"ln", b"-f", &sh_quote(&action.dest_entry.path), &path],
+                )
+            }
+            FileCommand::Symlink => {
+                let target = symlink_target(action, invocation.cli.relative_symlinks);
+                write_command(out, &[b"rm", b"-f", &path])?;
+                write_command(out, &[b"ln", b"-s", &sh_quote(&target), &path])
+            }
+        }
+    }
+
+    fn write_footer(
+        &self,
+        out: &mut dyn Write,
+        actions: &[Action],
+        saved_space: &str,
+        invocation: &Invocation,
+    ) -> io::Result<()> {
+        write_totals(out, actions, saved_space, invocation.cli)?;
+        if invocation.command == FileCommand::Rsync {
+            // Every listed file is missing from the empty source, so rsync deletes it from /
+            out.write_all("\nempty_dir=$(mktemp -d)\n".as_bytes())?;
+            write_command(
+                out,
+                &[
+                    b"rsync -r --delete-missing-args --from0",
+                    &[
+                        b"--files-from=".as_slice(),
+                        &sh_quote(invocation.file_list_path),
+                    ]
+                    .concat(),
+                    b"\"$empty_dir/\" /",
+                ],
+            )?;
+            out.write_all("rmdir \"$empty_dir\"\n".as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn write_undo(
+        &self,
+        out: &mut dyn Write,
+        actions: &[Action],
+        invocation: &Invocation,
+    ) -> io::Result<()> {
+        out.write_all("#! /bin/env sh\n\n".as_bytes())?;
+        writeln!(out, "# Reverts actions from {:?}", invocation.script_path)?;
+        for action in actions.iter().rev() {
+            writeln!(out, "\n# source: {:?}", action.entry.path)?;
+            let path = sh_quote(&action.entry.path);
+            match action.action {
+                FileCommand::Move => write_command(
+                    out,
+                    &[
+                        b"mv",
+                        b"-n",
+                        &sh_quote(action.target.as_ref().unwrap()),
+                        &path,
+                    ],
+                )?,
+                FileCommand::Copy => {
+                    write_command(out, &[b"rm", &sh_quote(action.target.as_ref().unwrap())])?;
+                }
+                FileCommand::Delete | FileCommand::Rsync => {
+                    out.write_all("# deleted, cannot be undone\n".as_bytes())?;
+                }
+                FileCommand::Print => {}
+                // The content is still there, give the path its own copy again
+                FileCommand::Hardlink | FileCommand::Symlink => {
+                    write_command(out, &[b"rm", &path])?;
+                    write_command(
+                        out,
+                        &[b"cp", b"-p", &sh_quote(&action.dest_entry.path), &path],
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a `.ps1` script for Windows. Paths are passed with `-LiteralPath` so brackets in
+/// file names aren't taken as wildcards.
+pub struct PowerShellWriter;
+
+impl ActionWriter for PowerShellWriter {
+    fn write_header(&self, out: &mut dyn Write, invocation: &Invocation) -> io::Result<()> {
+        out.write_all("#!/usr/bin/env pwsh\n\n".as_bytes())?;
+        if !invocation.cli.no_comments {
+            write_comment(
+                out,
+                &command_line(invocation, |path| ps_quote(path).into_bytes()),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_action(
+        &self,
+        out: &mut dyn Write,
+        action: &Action,
+        invocation: &Invocation,
+    ) -> io::Result<()> {
+        write_action_comments(out, action, invocation.cli)?;
+        let path = ps_quote(&action.entry.path);
+        match action.action {
+            FileCommand::Move | FileCommand::Copy => {
+                let target = action.target.as_ref().expect("Move without a target");
+                let cmdlet = if action.action == FileCommand::Move {
+                    "Move-Item"
+                } else {
+                    "Copy-Item"
+                };
+                // Like `mv -n`, an existing target is left alone
+                out.write_fmt(format_args!(
+                    "New-Item -ItemType Directory -Force -Path {} | Out-Null\n\
+                     if (-not (Test-Path -LiteralPath {})) {{ {} -LiteralPath {} -Destination {} }}\n",
+                    ps_quote(target.parent().unwrap_or(Path::new("."))),
+                    ps_quote(target),
+                    cmdlet,
+                    path,
+                    ps_quote(target)
+                ))
+            }
+            FileCommand::Delete => writeln!(out, "{}", remove_item(&path)),
+            FileCommand::Print => writeln!(out, "Write-Output {}", path),
+            // Deleted all at once in the footer
+            FileCommand::Rsync => Ok(()),
+            FileCommand::Hardlink | FileCommand::Symlink => {
+                let (item_type, target) = if action.action == FileCommand::Hardlink {
+                    ("HardLink", action.dest_entry.path.clone())
+                } else {
+                    (
+                        "SymbolicLink",
+                        symlink_target(action, invocation.cli.relative_symlinks),
+                    )
+                };
+                out.write_fmt(format_args!(
+                    "{}\nNew-Item -ItemType {} -Path {} -Target {} | Out-Null\n",
+                    remove_item(&path),
+                    item_type,
+                    path,
+                    ps_quote(&target)
+                ))
+            }
+        }
+    }
+
+    fn write_footer(
+        &self,
+        out: &mut dyn Write,
+        actions: &[Action],
+        saved_space: &str,
+        invocation: &Invocation,
+    ) -> io::Result<()> {
+        write_totals(out, actions, saved_space, invocation.cli)?;
+        if invocation.command == FileCommand::Rsync {
+            // There is no rsync on Windows, the same file list is removed one by one instead
+            out.write_fmt(format_args!(
+                "\n(Get-Content -LiteralPath {} -Raw).Split([char]0) | Where-Object {{ $_ }} | \
+                 ForEach-Object {{ {} }}\n",
+                ps_quote(invocation.file_list_path),
+                remove_item("$_")
+            ))?;
+        }
+        Ok(())
+    }
+
+    fn write_undo(
+        &self,
+        out: &mut dyn Write,
+        actions: &[Action],
+        invocation: &Invocation,
+    ) -> io::Result<()> {
+        out.write_all("#!/usr/bin/env pwsh\n\n".as_bytes())?;
+        writeln!(out, "# Reverts actions from {:?}", invocation.script_path)?;
+        for action in actions.iter().rev() {
+            writeln!(out, "\n# source: {:?}", action.entry.path)?;
+            let path = ps_quote(&action.entry.path);
+            match action.action {
+                FileCommand::Move => writeln!(
+                    out,
+                    "if (-not (Test-Path -LiteralPath {})) {{ Move-Item -LiteralPath {} -Destination {} }}",
+                    path,
+                    ps_quote(action.target.as_ref().unwrap()),
+                    path
+                )?,
+                FileCommand::Copy => writeln!(
+                    out,
+                    "Remove-Item -LiteralPath {}",
+                    ps_quote(action.target.as_ref().unwrap())
+                )?,
+                FileCommand::Delete | FileCommand::Rsync => {
+                    out.write_all("# deleted, cannot be undone\n".as_bytes())?;
+                }
+                FileCommand::Print => {}
+                // The content is still there, give the path its own copy again
+                FileCommand::Hardlink | FileCommand::Symlink => writeln!(
+                    out,
+                    "Remove-Item -LiteralPath {}\nCopy-Item -LiteralPath {} -Destination {}",
+                    path,
+                    ps_quote(&action.dest_entry.path),
+                    path
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes JSON lines for other tools to carry out: the invocation, one object per action and
+/// the totals. Comments and annotations don't apply, every line carries the full details.
+pub struct JsonWriter;
+
+impl ActionWriter for JsonWriter {
+    fn write_header(&self, out: &mut dyn Write, invocation: &Invocation) -> io::Result<()> {
+        let cli = invocation.cli;
+        let line: Value = json!({
+            "type": "invocation",
+            "command": invocation.command.to_string(),
+            "mode": cli.mode.to_string(),
+            "src": cli.src.to_string_lossy(),
+            "dest": cli.dest.iter().map(|dest| dest.to_string_lossy()).collect::<Vec<_>>(),
+            "target_dir": cli.move_to.as_ref().or(cli.copy_to.as_ref()).map(|dir| dir.to_string_lossy()),
+        });
+        writeln!(out, "{}", line)
+    }
+
+    fn write_action(
+        &self,
+        out: &mut dyn Write,
+        action: &Action,
+        invocation: &Invocation,
+    ) -> io::Result<()> {
+        let mut line: Value = json!({
+            "type": "action",
+            "action": action.action.to_string(),
+            "path": action.entry.path.to_string_lossy(),
+            "keeper": action.dest_entry.path.to_string_lossy(),
+            "size": action.entry.metadata.file_metadata.file_size,
+        });
+        if let Some(target) = &action.target {
+            line["target"] = json!(target.to_string_lossy());
+        }
+        if action.action == FileCommand::Symlink {
+            let target = symlink_target(action, invocation.cli.relative_symlinks);
+            line["link_target"] = json!(target.to_string_lossy());
+        }
+        writeln!(out, "{}", line)
+    }
+
+    fn write_footer(
+        &self,
+        out: &mut dyn Write,
+        actions: &[Action],
+        saved_space: &str,
+        invocation: &Invocation,
+    ) -> io::Result<()> {
+        let mut line: Value = json!({
+            "type": "summary",
+            "actions": actions.len(),
+            "saved_space": saved_space,
+        });
+        if invocation.command == FileCommand::Rsync {
+            line["file_list"] = json!(invocation.file_list_path.to_string_lossy());
+        }
+        writeln!(out, "{}", line)
+    }
+
+    fn write_undo(
+        &self,
+        out: &mut dyn Write,
+        actions: &[Action],
+        invocation: &Invocation,
+    ) -> io::Result<()> {
+        let line: Value = json!({
+            "type": "invocation",
+            "reverts": invocation.script_path.to_string_lossy(),
+        });
+        writeln!(out, "{}", line)?;
+        for action in actions.iter().rev() {
+            let path = action.entry.path.to_string_lossy();
+            let line: Value = match action.action {
+                FileCommand::Move => json!({
+                    "type": "action",
+                    "action": FileCommand::Move.to_string(),
+                    "path": action.target.as_ref().unwrap().to_string_lossy(),
+                    "target": path,
+                }),
+                FileCommand::Copy => json!({
+                    "type": "action",
+                    "action": FileCommand::Delete.to_string(),
+                    "path": action.target.as_ref().unwrap().to_string_lossy(),
+                }),
+                FileCommand::Delete | FileCommand::Rsync => json!({
+                    "type": "irreversible",
+                    "path": path,
+                }),
+                FileCommand::Print => continue,
+                // The content is still there, give the path its own copy again
+                FileCommand::Hardlink | FileCommand::Symlink => json!({
+                    "type": "action",
+                    "action": FileCommand::Copy.to_string(),
+                    "path": action.dest_entry.path.to_string_lossy(),
+                    "target": path,
+                    "replace": true,
+                }),
+            };
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn is_executable(&self) -> bool {
+        false
+    }
+}
+
+/// The rcc invocation recorded at the top of a script, with paths quoted by `quote`.
+fn command_line(invocation: &Invocation, quote: impl Fn(&Path) -> Vec<u8>) -> Vec<u8> {
+    let cli = invocation.cli;
+    let mut line = b"rcc -o ".to_vec();
+    line.extend(quote(invocation.script_path));
+    line.extend(format!(" -c {}", invocation.command).as_bytes());
+    if let Some(dir) = &cli.move_to {
+        line.extend(b" --move-to ");
+        line.extend(quote(dir));
+    }
+    if let Some(dir) = &cli.copy_to {
+        line.extend(b" --copy-to ");
+        line.extend(quote(dir));
+    }
+    line.extend(b" --src ");
+    line.extend(quote(&cli.src));
+    for dest in &cli.dest {
+        line.extend(b" --dest ");
+        line.extend(quote(dest));
+    }
+    line
+}
+
+/// Writes `text` as `#` comments, a line break in a file name can't end the comment early.
+fn write_comment(out: &mut dyn Write, text: &[u8]) -> io::Result<()> {
+    for line in text.split(|&byte| byte == b'\n' || byte == b'\r') {
+        out.write_all(b"# ")?;
+        out.write_all(line)?;
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Comments above an action, shared by the formats that use `#` for them.
+fn write_action_comments(out: &mut dyn Write, action: &Action, cli: &Cli) -> io::Result<()> {
+    if !cli.no_comments {
+        writeln!(out, "\n# destination: {:?}", action.dest_entry.path)?;
+    }
+    // Dates and models come from the files themselves, a line break in them must not end the
+    // comment and leave the rest to be run
+    if cli.annotate {
+        let keeper = format!("keeper:    {}", describe_entry(&action.dest_entry));
+        write_comment(out, keeper.as_bytes())?;
+        let duplicate = format!("duplicate: {}", describe_entry(&action.entry));
+        write_comment(out, duplicate.as_bytes())?;
+        writeln!(out, "# matched in {} mode", cli.mode)?;
+    }
+    Ok(())
+}
+
+fn write_totals(
+    out: &mut dyn Write,
+    actions: &[Action],
+    saved_space: &str,
+    cli: &Cli,
+) -> io::Result<()> {
+    if !cli.no_comments {
+        writeln!(out, "\n# Total saved space: {}", saved_space)?;
+        writeln!(out, "# Total actions: {}", actions.len())?;
+    }
+    Ok(())
+}
+
+/// Single-quoted sh word. Nothing is expanded inside, so only `'` needs escaping, and the raw
+/// bytes of the path are kept even when they aren't valid UTF-8.
+fn sh_quote(path: &Path) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &byte in path.as_os_str().as_bytes() {
+        if byte == b'\'' {
+            quoted.extend_from_slice(b"'\\''");
+        } else {
+            quoted.push(byte);
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Writes a single sh command, `words` are separated by spaces.
+fn write_command(out: &mut dyn Write, words: &[&[u8]]) -> io::Result<()> {
+    out.write_all(&words.join(&b' '))?;
+    out.write_all(b"\n")
+}
+
+/// Single-quoted PowerShell string, nothing is expanded inside apart from doubled quotes.
+fn ps_quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', "''"))
+}
+
+/// Removal that, like `rm -f`, doesn't fail when the file is already gone.
+fn remove_item(quoted_path: &str) -> String {
+    format!("Remove-Item -LiteralPath {quoted_path} -Force -ErrorAction SilentlyContinue")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use rcc::{CollectedMetadata, Entry, FileMetadata, ImageMetadata};
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+
+    fn entry(path: &str, is_dest: bool) -> Entry {
+        let path = PathBuf::from(path);
+        let file_metadata = FileMetadata {
+            base_file_name: path.file_name().unwrap().to_string_lossy().into_owned(),
+            // Lowercased like the scan does
+            extension: "jpg".to_string(),
+            ..Default::default()
+        };
+        Entry {
+            canonical_path: path.clone(),
+            root: path.parent().unwrap().to_path_buf(),
+            path,
+            metadata: CollectedMetadata {
+                file_metadata,
+                ..Default::default()
+            },
+            is_dest,
+        }
+    }
+
+    #[test]
+    fn annotations_stay_in_comments() {
+        let cli = Cli::parse_from(["rcc", "--src", "/src", "--dest", "/dest", "--annotate"]);
+        let mut entry = entry("/src/IMG_0001.jpg", false);
+        entry.metadata.image_metadata = Some(ImageMetadata {
+            model: Some("EOS\ntouch /tmp/PWNED\rrm -rf ~".to_string()),
+            ..Default::default()
+        });
+        let action = Action {
+            dest_entry: entry.clone(),
+            entry,
+            action: FileCommand::Delete,
+            target: None,
+        };
+        let mut out = vec![];
+        write_action_comments(&mut out, &action, &cli).unwrap();
+        let comments = String::from_utf8(out).unwrap();
+        for line in comments.split(['\n', '\r']).filter(|line| !line.is_empty()) {
+            assert!(line.starts_with('#'), "{line:?} is not a comment");
+        }
+    }
+
+    #[test]
+    fn sh_quote_escapes_single_quotes() {
+        assert_eq!(
+            sh_quote(Path::new("it's $HOME.jpg")),
+            b"'it'\\''s $HOME.jpg'"
+        );
+    }
+
+    #[test]
+    fn sh_quote_keeps_non_utf8_bytes() {
+        let path = Path::new(OsStr::from_bytes(b"IMG_\xff.jpg"));
+        assert_eq!(sh_quote(path), b"'IMG_\xff.jpg'");
+    }
+
+    #[test]
+    fn script_keeps_extension_case() {
+        let cli = Cli::parse_from(["rcc", "--src", "/src", "--dest", "/dest", "-c", "move"]);
+        let action = Action {
+            entry: entry("/src/IMG_0001.JPG", false),
+            dest_entry: entry("/dest/IMG_0001.JPG", true),
+            action: FileCommand::Move,
+            target: Some(PathBuf::from("/moved/IMG_0001.JPG")),
+        };
+        let invocation = Invocation {
+            cli: &cli,
+            command: FileCommand::Move,
+            script_path: Path::new("rcc.sh"),
+            file_list_path: Path::new("rcc.list"),
+        };
+        let mut out = vec![];
+        ShWriter
+            .write_action(&mut out, &action, &invocation)
+            .unwrap();
+        let script = String::from_utf8(out).unwrap();
+        assert!(script.contains("'/src/IMG_0001.JPG' '/moved/IMG_0001.JPG'"));
+        assert!(!script.contains(".jpg"));
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::DuplicateGroup;
+
+const UNKNOWN_MODEL: &str = "(unknown)";
+
+#[derive(Default, Debug)]
+pub struct Stats {
+    pub files_scanned: usize,
+    pub image_files: usize,
+    pub video_files: usize,
+    pub skipped_files: usize,
+    pub bytes_scanned: u64,
+    pub duplicate_groups: usize,
+    pub overlapping_files: usize,
+    pub comparisons_avoided: u64,
+    pub scan_time: Duration,
+    pub compare_time: Duration,
+    pub limit_reached: bool,
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Summary:\n")?;
+        f.write_fmt(format_args!(
+            "  Files scanned:     {}\n",
+            self.files_scanned
+        ))?;
+        f.write_fmt(format_args!("  Image metadata:    {}\n", self.image_files))?;
+        f.write_fmt(format_args!("  Video metadata:    {}\n", self.video_files))?;
+        f.write_fmt(format_args!(
+            "  Skipped:           {}\n",
+            self.skipped_files
+        ))?;
+        f.write_fmt(format_args!(
+            "  Duplicate groups:  {}\n",
+            self.duplicate_groups
+        ))?;
+        if self.overlapping_files > 0 {
+            f.write_fmt(format_args!(
+                "  In src and dest:   {}\n",
+                self.overlapping_files
+            ))?;
+        }
+        f.write_fmt(format_args!(
+            "  Scan time:         {:.2?}\n",
+            self.scan_time
+        ))?;
+        f.write_fmt(format_args!(
+            "  Bytes scanned:     {} ({}/s)\n",
+            format_size(self.bytes_scanned),
+            format_size(self.scan_throughput())
+        ))?;
+        if self.comparisons_avoided > 0 {
+            f.write_fmt(format_args!(
+                "  Skipped by name:   {} comparisons\n",
+                self.comparisons_avoided
+            ))?;
+        }
+        f.write_fmt(format_args!(
+            "  Compare time:      {:.2?}",
+            self.compare_time
+        ))?;
+        if self.limit_reached {
+            f.write_str("\n  Comparison stopped early, --limit reached")?;
+        }
+        Ok(())
+    }
+}
+
+/// Prints how many images were taken with each camera model, most common first.
+pub fn print_model_breakdown<'a>(models: impl Iterator<Item = Option<&'a str>>) {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for model in models {
+        *counts.entry(model.unwrap_or(UNKNOWN_MODEL)).or_default() += 1;
+    }
+
+    let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+
+    let width = counts
+        .iter()
+        .map(|(model, _)| model.len())
+        .max()
+        .unwrap_or(0);
+    log!("Images by camera model:");
+    for (model, count) in counts {
+        log!("  {:<width$}  {}", model, count);
+    }
+}
+
+/// Prints how many duplicates were found in each top-level directory of the scanned roots,
+/// messiest first. Files directly in a root are counted under the root itself.
+pub fn print_directory_breakdown<'a>(victims: impl Iterator<Item = &'a Path>, roots: &[&Path]) {
+    let mut counts: HashMap<PathBuf, usize> = HashMap::new();
+    for victim in victims {
+        // Roots can be nested, the innermost one is the one that was scanned for this file
+        let root = roots
+            .iter()
+            .filter(|root| victim.starts_with(root))
+            .max_by_key(|root| root.components().count());
+        let directory = match root {
+            Some(root) => {
+                let mut components = victim.strip_prefix(root).unwrap().components();
+                match (components.next(), components.next()) {
+                    (Some(first), Some(_)) => root.join(first),
+                    // A file directly in the root has no directory component
+                    _ => root.to_path_buf(),
+                }
+            }
+            None => victim.parent().unwrap_or(victim).to_path_buf(),
+        };
+        *counts.entry(directory).or_default() += 1;
+    }
+
+    let mut counts: Vec<(PathBuf, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    log!("Duplicates by directory:");
+    for (directory, count) in counts {
+        log!("  {:>6}  {}", count, directory.display());
+    }
+}
+
+impl Stats {
+    /// Average bytes per second over the scan phase.
+    fn scan_throughput(&self) -> u64 {
+        let seconds = self.scan_time.as_secs_f64();
+        if seconds == 0.0 {
+            return 0;
+        }
+        (self.bytes_scanned as f64 / seconds) as u64
+    }
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, UNITS[unit])
+    } else {
+        format!("{:.2}{}", size, UNITS[unit])
+    }
+}
+
+/// Prints duplicate groups with the biggest space savings first.
+pub fn print_group_table(groups: &[DuplicateGroup]) {
+    let mut groups: Vec<&DuplicateGroup> = groups.iter().collect();
+    groups.sort_by_key(|group| std::cmp::Reverse(group.reclaimable_bytes()));
+
+    log!("Duplicate groups by reclaimable space:");
+    for group in groups {
+        log!(
+            "  {:>10}  keep: {}",
+            format_size(group.reclaimable_bytes()),
+            group.keeper.path.display()
+        );
+        for duplicate in &group.duplicates {
+            log!("  {:>10}        {}", "", duplicate.path.display());
+        }
+    }
+}
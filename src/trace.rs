@@ -0,0 +1,58 @@
+use anyhow::Result;
+use serde_json::{Value, json};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use crate::{CompareSettings, Entry, field_checks};
+
+// Only set with `--trace`, the comparison loop checks it before doing any extra work
+static TRACE: OnceLock<Mutex<BufWriter<File>>> = OnceLock::new();
+
+pub fn open(path: &Path) -> Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    // Watch mode compares in batches but keeps writing to the first trace
+    let _ = TRACE.set(Mutex::new(writer));
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    TRACE.get().is_some()
+}
+
+/// Writes a JSON line for a compared pair. `rejected_by` names the check that failed, a
+/// match lists the fields that agreed and a mismatch the ones that differed.
+pub fn record(
+    dest_entry: &Entry,
+    src_entry: &Entry,
+    rejected_by: Option<&str>,
+    settings: &CompareSettings,
+) {
+    let Some(trace) = TRACE.get() else {
+        return;
+    };
+    let matched = rejected_by.is_none();
+    let fields: Vec<&str> = field_checks(dest_entry, src_entry, settings)
+        .into_iter()
+        .filter(|(_, agreed)| *agreed == matched)
+        .map(|(field, _)| field)
+        .collect();
+    let line: Value = json!({
+        "dest": dest_entry.path.to_string_lossy(),
+        "src": src_entry.path.to_string_lossy(),
+        "outcome": if matched { "match" } else { "mismatch" },
+        "rejected_by": rejected_by,
+        "fields": fields,
+    });
+    let mut writer = trace.lock().unwrap();
+    // Losing a trace line isn't worth aborting the comparison over
+    let _ = writeln!(writer, "{}", line);
+}
+
+pub fn flush() -> Result<()> {
+    if let Some(trace) = TRACE.get() {
+        trace.lock().unwrap().flush()?;
+    }
+    Ok(())
+}
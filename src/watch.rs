@@ -0,0 +1,93 @@
+use anyhow::Result;
+use colored::Colorize;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+use crate::{
+    Cli, CompareSettings, Entry, VisitOptions, find_duplicates, get_metadata_nom, is_path_included,
+    modified_since, print_near_misses, trace,
+};
+
+// Files are usually written in several chunks, wait for them to settle before reading
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+// A steady stream of events, e.g. a long import, never settles, the batch is checked anyway
+const MAX_BATCH_AGE: Duration = Duration::from_secs(30);
+
+/// Watches the source directory and compares every new file against the destination entries.
+pub fn watch_source(cli: &Cli, dest_entries: &[Entry], settings: &CompareSettings) -> Result<()> {
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&cli.src, RecursiveMode::Recursive)?;
+    log!("\nWatching {} for new files", cli.src.display());
+
+    let visit_options = VisitOptions::from(cli);
+    let mut pending: BTreeSet<PathBuf> = BTreeSet::new();
+    let mut batch_started = Instant::now();
+    loop {
+        let settled = match rx.recv_timeout(SETTLE_TIME) {
+            Ok(event) => {
+                let event = event?;
+                if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                    if pending.is_empty() {
+                        batch_started = Instant::now();
+                    }
+                    pending.extend(event.paths);
+                }
+                // A file still being written fails to read and comes back with its next event
+                batch_started.elapsed() >= MAX_BATCH_AGE
+            }
+            Err(RecvTimeoutError::Timeout) => true,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        if !settled {
+            continue;
+        }
+        for path in std::mem::take(&mut pending) {
+            if path.is_file()
+                && is_path_included(&path, Some(&cli.src), &visit_options)
+                && cli.since.is_none_or(|since| modified_since(&path, since))
+            {
+                check_new_file(path, cli, dest_entries, settings);
+            }
+        }
+    }
+}
+
+fn check_new_file(path: PathBuf, cli: &Cli, dest_entries: &[Entry], settings: &CompareSettings) {
+    let metadata = match get_metadata_nom(&path, cli) {
+        Ok(metadata) => metadata,
+        Err(err) => {
+            log!("{}", format!("Skipping {path:?} due to {}", err).yellow());
+            return;
+        }
+    };
+    let entry = Entry::new(path, cli.src.clone(), metadata, false);
+    if !cli.quiet {
+        log!("Adding: {}", entry);
+    }
+
+    let mut near_misses = vec![];
+    for found in find_duplicates(
+        std::slice::from_ref(&entry),
+        dest_entries,
+        settings,
+        &mut near_misses,
+    ) {
+        log!(
+            "{}",
+            format!(
+                "Duplicate found for: {}: {}",
+                found.keeper.path.display(),
+                entry.path.display()
+            )
+            .green()
+        );
+    }
+    print_near_misses(&mut near_misses);
+    if let Err(err) = trace::flush() {
+        log!("{}", format!("Failed to write trace: {}", err).red());
+    }
+}